@@ -2,13 +2,14 @@ use anyhow::Result;
 
 use colored::Colorize;
 use rezoning_scraper::{
-    db::Database,
+    db::{self, Database},
     summarizer::{self},
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let db = Database::new_from_file("rezoning_scraper.db")?;
+    let db_path = db::resolve_path(std::env::var("REZONING_SCRAPER_DB").ok().as_deref())?;
+    let db = Database::new_from_file(db_path)?;
 
     let projects = db.get_projects()?.into_iter().rev().skip(22).take(3);
 
@@ -16,7 +17,7 @@ async fn main() -> Result<()> {
         let description_md =
             summarizer::html_to_markdown(proj.attributes.description.as_ref().unwrap());
 
-        let summary = summarizer::project_to_tweet(&proj).await?;
+        let summary = summarizer::project_to_tweet(&proj, summarizer::DEFAULT_MODEL).await?;
 
         println!("{}", "Original Description:".bold().green());
         println!("{}", proj.attributes.name);
@@ -1,166 +1,594 @@
-use std::{num::NonZero, vec};
+use std::{collections::HashSet, num::NonZero, sync::Mutex, time::Duration};
 
 use anyhow::{bail, Context, Result};
 use atrium_api::{
     app::bsky::{
         embed::{
             defs::AspectRatioData,
+            external,
             images::{self, ImageData},
         },
-        feed::post::RecordEmbedRefs,
+        feed::post::{RecordData, RecordEmbedRefs, ReplyRef, ReplyRefData},
     },
-    types::Union,
+    com::atproto::repo::strong_ref,
+    types::{string::Cid, BlobRef, Union},
 };
-use bsky_sdk::{rich_text::RichText, BskyAgent};
-use image::codecs::jpeg::JpegEncoder;
+use bsky_sdk::{agent::config::Config, rich_text::RichText, BskyAgent};
+use scraper::{Html, Selector};
+
+use crate::{
+    db::{BlueskyThread, Database, PostRef},
+    images::{compress_image_until_under_size, usable_image_url, GenericImageFilter, ImageCache},
+    models::{Project, ProjectUpdate, SummarizedProject},
+    notifier::Notifier,
+};
+
+// Bluesky allows at most 4 images per post
+const MAX_IMAGES_PER_POST: usize = 4;
+
+pub const DEFAULT_PDS_URL: &str = "https://bsky.social";
+
+/// Login details for a Bluesky account, possibly hosted on a self-hosted PDS
+pub struct BlueskyAuth {
+    /// A handle (`example.bsky.social`) or a DID (`did:plc:...`)
+    pub identifier: String,
+    pub password: String,
+    pub pds_url: String,
+    /// Logged in on first use and kept, so a long-running process doesn't log in for every post
+    session: Mutex<Option<BskyAgent>>,
+}
+
+impl BlueskyAuth {
+    pub fn new(identifier: &str, password: &str, pds_url: Option<&str>) -> Result<Self> {
+        let identifier = normalize_identifier(identifier)?;
+
+        let pds_url = pds_url
+            .map(|url| url.trim().trim_end_matches('/'))
+            .filter(|url| !url.is_empty())
+            .unwrap_or(DEFAULT_PDS_URL);
+        let parsed = reqwest::Url::parse(pds_url)
+            .with_context(|| format!("Invalid Bluesky PDS URL: {}", pds_url))?;
+        if parsed.scheme() != "https" && parsed.scheme() != "http" {
+            bail!("Bluesky PDS URL must be http(s): {}", pds_url);
+        }
+
+        if !is_app_password(password) {
+            eprintln!(
+                "Bluesky password does not look like an app password (xxxx-xxxx-xxxx-xxxx); \
+                 consider creating one at https://bsky.app/settings/app-passwords"
+            );
+        }
+
+        Ok(BlueskyAuth {
+            identifier,
+            password: password.to_string(),
+            pds_url: pds_url.to_string(),
+            session: Mutex::new(None),
+        })
+    }
+
+    /// The logged-in agent, logging in if there's no session yet. The agent refreshes its own
+    /// access token as it expires.
+    async fn agent(&self) -> Result<BskyAgent> {
+        if let Some(agent) = self.session.lock().unwrap().as_ref() {
+            return Ok(agent.clone());
+        }
+        let agent = login(self).await?;
+        *self.session.lock().unwrap() = Some(agent.clone());
+        Ok(agent)
+    }
+
+    /// Drops the session so the next post logs in again, in case the session is what failed
+    fn forget_session(&self) {
+        *self.session.lock().unwrap() = None;
+    }
+}
+
+/// Accepts `@handle`, `handle` or a DID and returns the form the PDS expects
+fn normalize_identifier(identifier: &str) -> Result<String> {
+    let identifier = identifier.trim();
+    let identifier = identifier.strip_prefix('@').unwrap_or(identifier);
+
+    if identifier.is_empty() {
+        bail!("Bluesky user is empty");
+    }
+
+    if identifier.starts_with("did:") {
+        atrium_api::types::string::Did::new(identifier.to_string())
+            .map_err(|e| anyhow::anyhow!("Invalid Bluesky DID {}: {}", identifier, e))?;
+        Ok(identifier.to_string())
+    } else {
+        // handles are case-insensitive, but the PDS only accepts the normalized form
+        Ok(identifier.to_lowercase())
+    }
+}
+
+/// App passwords are generated by Bluesky in the form `xxxx-xxxx-xxxx-xxxx`
+fn is_app_password(password: &str) -> bool {
+    let groups: Vec<&str> = password.split('-').collect();
+    groups.len() == 4
+        && groups
+            .iter()
+            .all(|g| g.len() == 4 && g.chars().all(|c| c.is_ascii_alphanumeric()))
+}
 
-use crate::models::Project;
+async fn login(auth: &BlueskyAuth) -> Result<BskyAgent> {
+    let agent = BskyAgent::builder()
+        .config(Config {
+            endpoint: auth.pds_url.clone(),
+            ..Default::default()
+        })
+        .build()
+        .await?;
 
-// Hard limit on image size to post to Bluesky
-const MAX_IMAGE_SIZE_BYTES: usize = 1_000_000;
+    // TODO: persist the token?
+    _ = agent
+        .login(&auth.identifier, &auth.password)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to log in to {} as {}",
+                auth.pds_url, auth.identifier
+            )
+        })?;
+
+    Ok(agent)
+}
 
+/// Posts a new top-level post about a project, returning a reference to it so that later
+/// updates can be threaded underneath
 pub async fn post_to_bluesky(
     project: &Project,
     tweet_text: &str,
-    username: &str,
-    password: &str,
-) -> Result<()> {
-    let agent = BskyAgent::builder().build().await?;
+    auth: &BlueskyAuth,
+    filter: &GenericImageFilter<'_>,
+    image_cache: &ImageCache<'_>,
+) -> Result<PostRef> {
+    let agent = auth.agent().await?;
+
+    let text = match project.post_prefix() {
+        Some(prefix) => format!("{}: {}", prefix, tweet_text),
+        None => tweet_text.to_string(),
+    };
 
-    // TODO: persist the token?
-    _ = agent.login(username, password).await?;
-
-    let mut embed = None;
-
-    if let Some(img_url) = &project.attributes.image_url {
-        // sometimes they post generic images that we don't want to repost
-        if !img_url.trim().is_empty() && !img_url.to_lowercase().contains("generic") {
-            let img_bytes = reqwest::get(img_url).await?.bytes().await?;
-            eprintln!("Downloaded image: {}", img_url);
-
-            let img_bytes = compress_image_until_under_size(&img_bytes)?;
-
-            let img = image::load_from_memory(&img_bytes)?;
-            let height = NonZero::new(img.height() as u64).context("Image height is zero")?;
-            let width = NonZero::new(img.width() as u64).context("Image width is zero")?;
-            let aspect_ratio = AspectRatioData { height, width };
-            eprintln!("Calculated aspect ratio: {}x{}", width, height);
-
-            let output = agent
-                .api
-                .com
-                .atproto
-                .repo
-                .upload_blob(img_bytes.to_vec())
-                .await?;
-            eprintln!("Uploaded image");
-
-            let image = ImageData {
-                alt: project
-                    .attributes
-                    .image_description
-                    .clone()
-                    .unwrap_or("Image from ShapeYourCity API".to_string()),
-                aspect_ratio: Some(aspect_ratio.into()),
-                image: output.data.blob,
+    let mut images = Vec::new();
+
+    // The banner and description images count too, even when there's no main image
+    let candidates = collect_images(project);
+    if !candidates.is_empty() {
+        let mut last_error = None;
+
+        for candidate in candidates {
+            match upload_image(&agent, &candidate.url, &project.id, filter, image_cache).await {
+                Ok(Some((blob, aspect_ratio))) => images.push(
+                    ImageData {
+                        alt: candidate.alt,
+                        aspect_ratio: Some(aspect_ratio.into()),
+                        image: blob,
+                    }
+                    .into(),
+                ),
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("Failed to upload image {}: {}", candidate.url, e);
+                    last_error = Some(e);
+                }
             }
-            .into();
-
-            let images = vec![image];
+        }
 
-            embed = Some(Union::Refs(RecordEmbedRefs::AppBskyEmbedImagesMain(
-                Box::new(images::MainData { images }.into()),
-            )));
+        // If every image turned out to be generic we fall back to a link card below
+        if let (true, Some(e)) = (images.is_empty(), last_error) {
+            return Err(e);
         }
     }
 
-    let tags = &project.attributes.project_tag_list;
-    let tweet_with_link = if tags.iter().any(|tag| tag == "Development") {
-        format!("DP: {} {}", tweet_text, project.links.self_link)
-    } else if tags.iter().any(|tag| tag == "Rezoning") {
-        format!("Rezoning: {} {}", tweet_text, project.links.self_link)
+    let (text, embed) = if !images.is_empty() {
+        let embed = Union::Refs(RecordEmbedRefs::AppBskyEmbedImagesMain(Box::new(
+            images::MainData { images }.into(),
+        )));
+
+        (format!("{} {}", text, project.links.self_link), embed)
     } else {
-        format!("{} {}", tweet_text, project.links.self_link)
+        // No image worth posting; link to the project with a card instead, which also saves
+        // the characters the URL would take up in the text
+        let mut card = external_card(project, tweet_text);
+
+        if let Some(banner_url) = usable_image_url(&project.attributes.banner_url) {
+            match upload_image(&agent, banner_url, &project.id, filter, image_cache).await {
+                Ok(Some((blob, _))) => card.thumb = Some(blob),
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to upload card thumbnail {}: {}", banner_url, e),
+            }
+        }
+
+        let embed = Union::Refs(RecordEmbedRefs::AppBskyEmbedExternalMain(Box::new(
+            external::MainData {
+                external: card.into(),
+            }
+            .into(),
+        )));
+
+        (text, embed)
+    };
+
+    eprintln!("Tweeting: {}", text);
+
+    create_post(&agent, text, Some(embed), None).await
+}
+
+struct ImageCandidate {
+    url: String,
+    alt: String,
+}
+
+/// Gathers up to `MAX_IMAGES_PER_POST` images for a project: the main image first, then the
+/// banner, then any images embedded in the description
+fn collect_images(project: &Project) -> Vec<ImageCandidate> {
+    let attributes = &project.attributes;
+
+    let main_alt = project
+        .image_alt_text()
+        .unwrap_or("Image from ShapeYourCity API")
+        .to_string();
+
+    let mut candidates = Vec::new();
+
+    if let Some(url) = usable_image_url(&attributes.image_url) {
+        candidates.push(ImageCandidate {
+            url: url.to_string(),
+            alt: main_alt,
+        });
+    }
+
+    if let Some(url) = usable_image_url(&attributes.banner_url) {
+        candidates.push(ImageCandidate {
+            url: url.to_string(),
+            alt: format!("Banner image for {}", attributes.name.replace('\n', "")),
+        });
+    }
+
+    let description = Html::parse_fragment(attributes.description.as_deref().unwrap_or_default());
+    let selector = Selector::parse("img[src]").unwrap();
+    for img in description.select(&selector) {
+        let src = img.value().attr("src").map(str::to_string);
+        if let Some(url) = usable_image_url(&src) {
+            let alt = img
+                .value()
+                .attr("alt")
+                .map(str::trim)
+                .filter(|alt| !alt.is_empty())
+                .unwrap_or("Image from project description");
+            candidates.push(ImageCandidate {
+                url: url.to_string(),
+                alt: alt.to_string(),
+            });
+        }
+    }
+
+    // The same image often shows up in several places, sometimes with a different cache-busting
+    // query string
+    let mut seen = HashSet::new();
+    candidates.retain(|c| seen.insert(c.url.split('?').next().unwrap_or(&c.url).to_string()));
+    candidates.truncate(MAX_IMAGES_PER_POST);
+
+    candidates
+}
+
+/// Builds website card metadata from what the API gives us, no scraping needed
+fn external_card(project: &Project, tweet_text: &str) -> external::ExternalData {
+    let description = project
+        .attributes
+        .meta_description
+        .as_deref()
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .unwrap_or(tweet_text);
+
+    external::ExternalData {
+        description: description.to_string(),
+        thumb: None,
+        title: project.attributes.name.replace('\n', ""),
+        uri: project.links.self_link.clone(),
+    }
+}
+
+/// Uploads an image, or returns `None` if it's one of the city's generic images
+async fn upload_image(
+    agent: &BskyAgent,
+    img_url: &str,
+    project_id: &str,
+    filter: &GenericImageFilter<'_>,
+    image_cache: &ImageCache<'_>,
+) -> Result<Option<(BlobRef, AspectRatioData)>> {
+    let img_bytes = image_cache.get(img_url).await?;
+
+    if filter.is_generic(project_id, img_url, &img_bytes)? {
+        eprintln!("Skipping generic image: {}", img_url);
+        return Ok(None);
+    }
+
+    let img = compress_image_until_under_size(&img_bytes)?;
+
+    let height = NonZero::new(img.height as u64).context("Image height is zero")?;
+    let width = NonZero::new(img.width as u64).context("Image width is zero")?;
+    let aspect_ratio = AspectRatioData { height, width };
+    eprintln!("Calculated aspect ratio: {}x{}", width, height);
+
+    let output = agent.api.com.atproto.repo.upload_blob(img.bytes).await?;
+    eprintln!("Uploaded image");
+
+    Ok(Some((output.data.blob, aspect_ratio)))
+}
+
+/// Posts a change notification as a reply in the thread started by the original post
+pub async fn post_update_to_bluesky(
+    update: &ProjectUpdate,
+    thread: &BlueskyThread,
+    auth: &BlueskyAuth,
+) -> Result<PostRef> {
+    let agent = auth.agent().await?;
+
+    let reply = ReplyRefData {
+        root: thread.root.to_strong_ref()?,
+        parent: thread.latest.to_strong_ref()?,
     };
 
-    eprintln!("Tweeting: {}", tweet_with_link);
+    let text = update_text(update);
+    eprintln!("Replying: {}", text);
 
-    let rt = RichText::new_with_detect_facets(tweet_with_link).await?;
+    create_post(&agent, text, None, Some(reply.into())).await
+}
 
-    agent
-        .create_record(atrium_api::app::bsky::feed::post::RecordData {
+async fn create_post(
+    agent: &BskyAgent,
+    text: String,
+    embed: Option<Union<RecordEmbedRefs>>,
+    reply: Option<ReplyRef>,
+) -> Result<PostRef> {
+    let rt = RichText::new_with_detect_facets(text).await?;
+
+    let output = agent
+        .create_record(RecordData {
             created_at: atrium_api::types::string::Datetime::now(),
             embed,
             entities: None,
             facets: rt.facets,
             labels: None,
             langs: None,
-            reply: None,
+            reply,
             tags: None,
             text: rt.text,
         })
         .await?;
 
-    Ok(())
+    Ok(PostRef {
+        uri: output.data.uri,
+        cid: output.data.cid.as_ref().to_string(),
+    })
 }
 
-fn compress_image_until_under_size(img: &[u8]) -> Result<Vec<u8>> {
-    if img.len() < MAX_IMAGE_SIZE_BYTES {
-        return Ok(img.to_vec());
-    }
+/// Describes what changed about a project, e.g. "Update: status changed from published to archived"
+fn update_text(update: &ProjectUpdate) -> String {
+    format!(
+        "Update: {} {}",
+        update.describe_changes(),
+        update.project.links.self_link
+    )
+}
 
-    let img = image::load_from_memory(img)?;
-    let mut buffer = vec![];
-    let mut quality = 90;
+impl PostRef {
+    fn to_strong_ref(&self) -> Result<strong_ref::Main> {
+        let cid = self
+            .cid
+            .parse::<Cid>()
+            .map_err(|e| anyhow::anyhow!("Invalid CID {}: {}", self.cid, e))?;
 
-    loop {
-        buffer.clear();
+        Ok(strong_ref::MainData {
+            cid,
+            uri: self.uri.clone(),
+        }
+        .into())
+    }
+}
 
-        JpegEncoder::new_with_quality(&mut buffer, quality).encode_image(&img)?;
+pub struct BlueskyNotifier<'a> {
+    pub auth: &'a BlueskyAuth,
+    pub generic_image_hashes: &'a [u64],
+    pub image_client: &'a reqwest::Client,
+}
 
-        eprintln!(
-            "Resized image (quality: {}) size: {}b",
-            quality,
-            buffer.len()
-        );
+impl Notifier for BlueskyNotifier<'_> {
+    type Message = SummarizedProject;
+
+    const NAME: &'static str = "Bluesky";
+    const QUEUE_NAME: &'static str = "bluesky_post_queue";
+
+    async fn send(&self, db: &Database, project: &SummarizedProject) -> Result<()> {
+        let post = post_to_bluesky(
+            &project.project,
+            &project.tweet,
+            self.auth,
+            &GenericImageFilter::new(db, self.generic_image_hashes),
+            &ImageCache::new(db, self.image_client),
+        )
+        .await
+        .inspect_err(|_| self.auth.forget_session())?;
+        db.record_bluesky_post(&project.project.id, &post)
+    }
 
-        if buffer.len() < MAX_IMAGE_SIZE_BYTES {
-            return Ok(buffer);
-        }
+    // avoid hitting the API too hard
+    fn rate_limit(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1))
+    }
+}
 
-        quality = quality.saturating_sub(5);
+/// Replies to the original post's thread when a project changes
+pub struct BlueskyUpdateNotifier<'a> {
+    pub auth: &'a BlueskyAuth,
+}
 
-        if quality == 0 {
-            break;
-        }
+impl Notifier for BlueskyUpdateNotifier<'_> {
+    type Message = ProjectUpdate;
+
+    const NAME: &'static str = "Bluesky updates";
+    const QUEUE_NAME: &'static str = "bluesky_update_queue";
+
+    async fn send(&self, db: &Database, update: &ProjectUpdate) -> Result<()> {
+        let project_id = &update.project.id;
+        let Some(thread) = db.get_bluesky_thread(project_id)? else {
+            eprintln!(
+                "No Bluesky thread for project {}; skipping update",
+                project_id
+            );
+            return Ok(());
+        };
+
+        let post = post_update_to_bluesky(update, &thread, self.auth)
+            .await
+            .inspect_err(|_| self.auth.forget_session())?;
+        db.record_bluesky_post(project_id, &post)
     }
 
-    bail!(
-        "Failed to compress image to under {}b",
-        MAX_IMAGE_SIZE_BYTES
-    )
+    fn rate_limit(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use crate::models::ProjectChange;
 
     #[test]
-    fn resize_image() {
-        let img_bytes = include_bytes!("../test_files/too_big.jpg");
+    fn collects_images_from_all_sources() {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = "123 Main St".to_string();
+        project.attributes.image_url = Some("https://example.com/main.jpg?1".to_string());
+        project.attributes.image_caption = Some("Rendering of 123 Main St".to_string());
+        project.attributes.banner_url = Some("https://example.com/banner.jpg".to_string());
+        project.attributes.description = Some(
+            r#"<p><img src="https://example.com/main.jpg?2"><img src="https://example.com/a.png" alt="Site plan">
+            <img src="https://example.com/generic.png"><img src="https://example.com/b.png">
+            <img src="https://example.com/c.png"></p>"#
+                .to_string(),
+        );
 
-        eprintln!("Image size: {}b", img_bytes.len());
+        let images = collect_images(&project);
+        let urls: Vec<&str> = images.iter().map(|i| i.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/main.jpg?1",
+                "https://example.com/banner.jpg",
+                "https://example.com/a.png",
+                "https://example.com/b.png",
+            ]
+        );
 
-        assert!(img_bytes.len() > MAX_IMAGE_SIZE_BYTES);
+        let alts: Vec<&str> = images.iter().map(|i| i.alt.as_str()).collect();
+        assert_eq!(
+            alts,
+            vec![
+                "Rendering of 123 Main St",
+                "Banner image for 123 Main St",
+                "Site plan",
+                "Image from project description",
+            ]
+        );
 
-        let buffer = compress_image_until_under_size(img_bytes).unwrap();
-        eprintln!("Resized image size: {}b", buffer.len());
+        // without a main image the rest are still used
+        project.attributes.image_url = None;
+        let images = collect_images(&project);
+        assert_eq!(images.len(), 4);
+        assert_eq!(images[0].url, "https://example.com/banner.jpg");
+    }
 
-        // write buffer to disk as resized.jpg
-        let mut file = std::fs::File::create("resized.jpg").unwrap();
-        file.write_all(buffer.as_slice()).unwrap();
+    #[test]
+    fn builds_external_card_from_project() {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = "123 Main St\n rezoning".to_string();
+        project.links.self_link = "https://shapeyourcity.ca/foo".to_string();
+
+        let card = external_card(&project, "6 storeys, 40 units");
+        assert_eq!(card.title, "123 Main St rezoning");
+        assert_eq!(card.uri, "https://shapeyourcity.ca/foo");
+        assert_eq!(card.description, "6 storeys, 40 units");
+
+        project.attributes.meta_description = Some("A rezoning application".to_string());
+        let card = external_card(&project, "6 storeys, 40 units");
+        assert_eq!(card.description, "A rezoning application");
+    }
 
-        // todo!()
+    #[test]
+    fn describes_updates() {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.links.self_link = "https://shapeyourcity.ca/foo".to_string();
+
+        let update = ProjectUpdate {
+            project,
+            changes: vec![
+                ProjectChange {
+                    field: "state".to_string(),
+                    old_value: "published".to_string(),
+                    new_value: "archived".to_string(),
+                },
+                ProjectChange {
+                    field: "description".to_string(),
+                    old_value: "old".to_string(),
+                    new_value: "new".to_string(),
+                },
+            ],
+        };
+
+        assert_eq!(
+            update_text(&update),
+            "Update: status changed from published to archived, description was revised https://shapeyourcity.ca/foo"
+        );
+    }
+
+    #[test]
+    fn normalizes_identifiers() {
+        assert_eq!(
+            normalize_identifier("@Example.bsky.social").unwrap(),
+            "example.bsky.social"
+        );
+        assert_eq!(
+            normalize_identifier(" did:plc:z72i7hdynmk6r22z27h6tvur ").unwrap(),
+            "did:plc:z72i7hdynmk6r22z27h6tvur"
+        );
+        assert!(normalize_identifier("did:nope").is_err());
+        assert!(normalize_identifier("@").is_err());
+    }
+
+    #[test]
+    fn detects_app_passwords() {
+        assert!(is_app_password("abcd-1234-efgh-5678"));
+        assert!(!is_app_password("hunter2"));
+        assert!(!is_app_password("abcd-1234-efgh"));
+    }
+
+    #[test]
+    fn pds_url_defaults_and_validates() {
+        let auth = BlueskyAuth::new("example.bsky.social", "abcd-1234-efgh-5678", None).unwrap();
+        assert_eq!(auth.pds_url, DEFAULT_PDS_URL);
+
+        let auth = BlueskyAuth::new(
+            "example.com",
+            "abcd-1234-efgh-5678",
+            Some("http://localhost:2583/"),
+        )
+        .unwrap();
+        assert_eq!(auth.pds_url, "http://localhost:2583");
+
+        assert!(BlueskyAuth::new("example.com", "pw", Some("ftp://example.com")).is_err());
     }
 }
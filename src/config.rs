@@ -0,0 +1,602 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    email::{self, DigestSchedule, Subscriber},
+    ntfy::{self, NtfyTopic},
+    rules::{self, Rule},
+    schedule::{self, QuietHours},
+};
+
+/// The `--config` file. Everything in it can also be set with command line flags or environment
+/// variables, which take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// A database name or path, as for --db
+    pub db: Option<String>,
+    /// ShapeYourCity site IDs to announce projects from; all of them if empty
+    #[serde(default)]
+    pub sites: Vec<String>,
+    #[serde(default)]
+    pub summarizer: SummarizerConfig,
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    #[serde(default)]
+    pub channels: ChannelsConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SummarizerConfig {
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub email_digest: Option<DigestSchedule>,
+    /// For `daemon`
+    pub interval_minutes: Option<u64>,
+    pub jitter_minutes: Option<u64>,
+    pub quiet_hours: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelsConfig {
+    pub slack: Option<SlackConfig>,
+    pub discord: Option<DiscordConfig>,
+    pub matrix: Option<MatrixConfig>,
+    pub telegram: Option<TelegramConfig>,
+    pub ntfy: Option<NtfyConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub bluesky: Option<BlueskyConfig>,
+    pub mastodon: Option<MastodonConfig>,
+    pub email: Option<EmailConfig>,
+}
+
+/// A credential, either written inline or read from an environment variable so the file can be
+/// checked in: `token = { env = "SLACK_BOT_TOKEN" }`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    Env { env: String },
+}
+
+// Every channel also has `enabled` (default true) and `rules`, which are `--rule`s without the
+// channel, e.g. `include:tag=Rezoning`
+fn enabled_by_default() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlackConfig {
+    pub webhook_url: Option<Secret>,
+    pub bot_token: Option<Secret>,
+    pub channel: Option<String>,
+    /// tag = channel
+    #[serde(default)]
+    pub tag_channels: BTreeMap<String, String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiscordConfig {
+    pub webhook_url: Secret,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixConfig {
+    pub homeserver_url: String,
+    pub access_token: Secret,
+    pub room_id: String,
+    #[serde(default)]
+    pub upload_images: bool,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TelegramConfig {
+    pub bot_token: Secret,
+    pub chat_ids: Vec<String>,
+    pub api_url: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NtfyConfig {
+    pub url: Option<String>,
+    pub token: Option<Secret>,
+    /// `--ntfy-topic` values, e.g. `kits:tag=Rezoning;keyword=Kitsilano`
+    pub topics: Vec<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: Secret,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlueskyConfig {
+    pub user: String,
+    pub app_password: Secret,
+    pub pds_url: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MastodonConfig {
+    pub instance_url: String,
+    pub access_token: Secret,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub smtp_url: Secret,
+    pub username: Option<Secret>,
+    pub password: Option<Secret>,
+    pub from: String,
+    /// `--email-subscriber` values, e.g. `me@example.com=Rezoning|Development`
+    pub subscribers: Vec<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub rules: Vec<String>,
+}
+
+/// The config file resolved into the values of the matching command line flags. `None` means
+/// the file doesn't set it.
+#[derive(Debug, Default)]
+pub struct Settings {
+    pub db: Option<String>,
+    pub site: Option<Vec<String>>,
+    pub summarizer_model: Option<String>,
+    pub email_digest: Option<DigestSchedule>,
+    pub interval_minutes: Option<u64>,
+    pub jitter_minutes: Option<u64>,
+    pub quiet_hours: Option<QuietHours>,
+    pub rule: Option<Vec<Rule>>,
+
+    pub slack_webhook_url: Option<String>,
+    pub slack_bot_token: Option<String>,
+    pub slack_channel: Option<String>,
+    pub slack_tag_channel: Option<Vec<(String, String)>>,
+    pub discord_webhook_url: Option<String>,
+    pub matrix_homeserver_url: Option<String>,
+    pub matrix_access_token: Option<String>,
+    pub matrix_room_id: Option<String>,
+    pub matrix_upload_images: Option<bool>,
+    pub telegram_bot_token: Option<String>,
+    pub telegram_chat_id: Option<Vec<String>>,
+    pub telegram_api_url: Option<String>,
+    pub ntfy_topic: Option<Vec<NtfyTopic>>,
+    pub ntfy_url: Option<String>,
+    pub ntfy_token: Option<String>,
+    pub webhook_url: Option<String>,
+    pub webhook_secret: Option<String>,
+    pub bluesky_user: Option<String>,
+    pub bluesky_app_password: Option<String>,
+    pub bluesky_pds_url: Option<String>,
+    pub mastodon_instance_url: Option<String>,
+    pub mastodon_access_token: Option<String>,
+    pub smtp_url: Option<String>,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub email_from: Option<String>,
+    pub email_subscriber: Option<Vec<Subscriber>>,
+
+    /// Channels the file sets up, for `config check`
+    pub channels: Vec<&'static str>,
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        // toml's errors point at the offending line and key
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Reads credentials from the environment and checks every value, reporting all problems at
+    /// once, each prefixed with the key it's about
+    pub fn resolve(&self) -> Result<Settings> {
+        self.resolve_with(|name| std::env::var(name).ok())
+    }
+
+    pub fn resolve_with(&self, env: impl Fn(&str) -> Option<String>) -> Result<Settings> {
+        let mut resolver = Resolver {
+            env: &env,
+            errors: Vec::new(),
+        };
+        let mut settings = Settings {
+            db: self.db.clone(),
+            site: (!self.sites.is_empty()).then(|| self.sites.clone()),
+            summarizer_model: self.summarizer.model.clone(),
+            email_digest: self.schedule.email_digest,
+            interval_minutes: self.schedule.interval_minutes,
+            jitter_minutes: self.schedule.jitter_minutes,
+            ..Default::default()
+        };
+        if self.schedule.interval_minutes == Some(0) {
+            resolver.error("schedule.interval_minutes", "must be at least 1");
+        }
+        if let Some(quiet_hours) = &self.schedule.quiet_hours {
+            settings.quiet_hours = resolver.parsed(
+                "schedule.quiet_hours",
+                quiet_hours,
+                schedule::parse_quiet_hours,
+            );
+        }
+        let mut rules = Vec::new();
+        let channels = &self.channels;
+
+        if let Some(slack) = channels.slack.as_ref().filter(|c| c.enabled) {
+            if slack.webhook_url.is_none() && slack.bot_token.is_none() {
+                resolver.error("channels.slack", "set webhook_url or bot_token");
+            }
+            if slack.bot_token.is_some() && slack.channel.is_none() {
+                resolver.error("channels.slack.channel", "required with bot_token");
+            }
+            settings.slack_webhook_url =
+                resolver.optional_secret("channels.slack.webhook_url", &slack.webhook_url);
+            settings.slack_bot_token =
+                resolver.optional_secret("channels.slack.bot_token", &slack.bot_token);
+            settings.slack_channel = slack.channel.clone();
+            settings.slack_tag_channel = Some(
+                slack
+                    .tag_channels
+                    .iter()
+                    .map(|(tag, channel)| (tag.clone(), channel.clone()))
+                    .collect(),
+            );
+            resolver.rules("slack", &slack.rules, &mut rules);
+            settings.channels.push("slack");
+        }
+
+        if let Some(discord) = channels.discord.as_ref().filter(|c| c.enabled) {
+            settings.discord_webhook_url =
+                resolver.secret("channels.discord.webhook_url", &discord.webhook_url);
+            resolver.rules("discord", &discord.rules, &mut rules);
+            settings.channels.push("discord");
+        }
+
+        if let Some(matrix) = channels.matrix.as_ref().filter(|c| c.enabled) {
+            settings.matrix_homeserver_url = Some(matrix.homeserver_url.clone());
+            settings.matrix_access_token =
+                resolver.secret("channels.matrix.access_token", &matrix.access_token);
+            settings.matrix_room_id = Some(matrix.room_id.clone());
+            settings.matrix_upload_images = Some(matrix.upload_images);
+            resolver.rules("matrix", &matrix.rules, &mut rules);
+            settings.channels.push("matrix");
+        }
+
+        if let Some(telegram) = channels.telegram.as_ref().filter(|c| c.enabled) {
+            if telegram.chat_ids.is_empty() {
+                resolver.error("channels.telegram.chat_ids", "must not be empty");
+            }
+            settings.telegram_bot_token =
+                resolver.secret("channels.telegram.bot_token", &telegram.bot_token);
+            settings.telegram_chat_id = Some(telegram.chat_ids.clone());
+            settings.telegram_api_url = telegram.api_url.clone();
+            resolver.rules("telegram", &telegram.rules, &mut rules);
+            settings.channels.push("telegram");
+        }
+
+        if let Some(ntfy) = channels.ntfy.as_ref().filter(|c| c.enabled) {
+            if ntfy.topics.is_empty() {
+                resolver.error("channels.ntfy.topics", "must not be empty");
+            }
+            let topics = ntfy
+                .topics
+                .iter()
+                .enumerate()
+                .filter_map(|(i, topic)| {
+                    resolver.parsed(
+                        &format!("channels.ntfy.topics[{}]", i),
+                        topic,
+                        ntfy::parse_topic,
+                    )
+                })
+                .collect();
+            settings.ntfy_topic = Some(topics);
+            settings.ntfy_url = ntfy.url.clone();
+            settings.ntfy_token = resolver.optional_secret("channels.ntfy.token", &ntfy.token);
+            resolver.rules("ntfy", &ntfy.rules, &mut rules);
+            settings.channels.push("ntfy");
+        }
+
+        if let Some(webhook) = channels.webhook.as_ref().filter(|c| c.enabled) {
+            settings.webhook_url = Some(webhook.url.clone());
+            settings.webhook_secret = resolver.secret("channels.webhook.secret", &webhook.secret);
+            resolver.rules("webhook", &webhook.rules, &mut rules);
+            settings.channels.push("webhook");
+        }
+
+        if let Some(bluesky) = channels.bluesky.as_ref().filter(|c| c.enabled) {
+            settings.bluesky_user = Some(bluesky.user.clone());
+            settings.bluesky_app_password =
+                resolver.secret("channels.bluesky.app_password", &bluesky.app_password);
+            settings.bluesky_pds_url = bluesky.pds_url.clone();
+            resolver.rules("bluesky", &bluesky.rules, &mut rules);
+            settings.channels.push("bluesky");
+        }
+
+        if let Some(mastodon) = channels.mastodon.as_ref().filter(|c| c.enabled) {
+            settings.mastodon_instance_url = Some(mastodon.instance_url.clone());
+            settings.mastodon_access_token =
+                resolver.secret("channels.mastodon.access_token", &mastodon.access_token);
+            resolver.rules("mastodon", &mastodon.rules, &mut rules);
+            settings.channels.push("mastodon");
+        }
+
+        if let Some(email) = channels.email.as_ref().filter(|c| c.enabled) {
+            if email.subscribers.is_empty() {
+                resolver.error("channels.email.subscribers", "must not be empty");
+            }
+            let subscribers = email
+                .subscribers
+                .iter()
+                .enumerate()
+                .filter_map(|(i, subscriber)| {
+                    resolver.parsed(
+                        &format!("channels.email.subscribers[{}]", i),
+                        subscriber,
+                        email::parse_subscriber,
+                    )
+                })
+                .collect();
+            settings.smtp_url = resolver.secret("channels.email.smtp_url", &email.smtp_url);
+            settings.smtp_username =
+                resolver.optional_secret("channels.email.username", &email.username);
+            settings.smtp_password =
+                resolver.optional_secret("channels.email.password", &email.password);
+            settings.email_from = Some(email.from.clone());
+            settings.email_subscriber = Some(subscribers);
+            resolver.rules("email", &email.rules, &mut rules);
+            settings.channels.push("email");
+        }
+
+        settings.rule = (!rules.is_empty()).then_some(rules);
+
+        if resolver.errors.is_empty() {
+            Ok(settings)
+        } else {
+            Err(anyhow!(resolver.errors.join("\n")))
+        }
+    }
+}
+
+struct Resolver<'a> {
+    env: &'a dyn Fn(&str) -> Option<String>,
+    errors: Vec<String>,
+}
+
+impl Resolver<'_> {
+    fn error(&mut self, key: &str, message: &str) {
+        self.errors.push(format!("{}: {}", key, message));
+    }
+
+    fn secret(&mut self, key: &str, secret: &Secret) -> Option<String> {
+        match secret {
+            Secret::Value(value) => Some(value.clone()),
+            Secret::Env { env } => match (self.env)(env) {
+                Some(value) if !value.is_empty() => Some(value),
+                _ => {
+                    self.error(key, &format!("environment variable {} is not set", env));
+                    None
+                }
+            },
+        }
+    }
+
+    fn optional_secret(&mut self, key: &str, secret: &Option<Secret>) -> Option<String> {
+        secret.as_ref().and_then(|s| self.secret(key, s))
+    }
+
+    fn parsed<T>(
+        &mut self,
+        key: &str,
+        value: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Option<T> {
+        match parse(value) {
+            Ok(parsed) => Some(parsed),
+            Err(e) => {
+                self.error(key, &e);
+                None
+            }
+        }
+    }
+
+    fn rules(&mut self, channel: &str, values: &[String], rules: &mut Vec<Rule>) {
+        for (i, rule) in values.iter().enumerate() {
+            let key = format!("channels.{}.rules[{}]", channel, i);
+            if let Some(rule) =
+                self.parsed(&key, &format!("{}:{}", channel, rule), rules::parse_rule)
+            {
+                rules.push(rule);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "SLACK_BOT_TOKEN" => Some("xoxb-123".to_string()),
+            "BLUESKY_APP_PASSWORD" => Some("app-pass".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn example_config_is_valid() -> Result<()> {
+        let config = Config::parse(include_str!("../rezoning-scraper.example.toml"))?;
+        let settings = config.resolve_with(|name| Some(format!("${}", name)))?;
+
+        assert_eq!(settings.db.as_deref(), Some("rezoning_scraper"));
+        assert_eq!(settings.slack_channel.as_deref(), Some("#rezonings"));
+        assert_eq!(
+            settings.slack_bot_token.as_deref(),
+            Some("$SLACK_BOT_TOKEN")
+        );
+        assert!(settings.channels.contains(&"bluesky"));
+        assert!(!settings.rule.unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_settings() -> Result<()> {
+        let config = Config::parse(
+            r##"
+            sites = ["526"]
+
+            [summarizer]
+            model = "gpt-4o-mini"
+
+            [schedule]
+            email_digest = "daily"
+            interval_minutes = 10
+            quiet_hours = "22:00-07:00"
+
+            [channels.slack]
+            bot_token = { env = "SLACK_BOT_TOKEN" }
+            channel = "#rezonings"
+            tag_channels = { Rezoning = "#rezoning-only" }
+            rules = ["exclude:state=archived"]
+
+            [channels.bluesky]
+            user = "rezoning.bsky.social"
+            app_password = { env = "BLUESKY_APP_PASSWORD" }
+            rules = ["include:tag=Rezoning"]
+
+            [channels.discord]
+            enabled = false
+            webhook_url = { env = "NOT_SET" }
+            "##,
+        )?;
+        let settings = config.resolve_with(env)?;
+
+        assert_eq!(settings.site, Some(vec!["526".to_string()]));
+        assert_eq!(settings.summarizer_model.as_deref(), Some("gpt-4o-mini"));
+        assert_eq!(settings.email_digest, Some(DigestSchedule::Daily));
+        assert_eq!(settings.interval_minutes, Some(10));
+        assert_eq!(
+            settings.quiet_hours,
+            Some(schedule::parse_quiet_hours("22:00-07:00").unwrap())
+        );
+        assert_eq!(settings.slack_bot_token.as_deref(), Some("xoxb-123"));
+        assert_eq!(
+            settings.slack_tag_channel,
+            Some(vec![("Rezoning".to_string(), "#rezoning-only".to_string())])
+        );
+        assert_eq!(settings.bluesky_app_password.as_deref(), Some("app-pass"));
+        // disabled channels aren't resolved, so their env vars needn't be set
+        assert_eq!(settings.discord_webhook_url, None);
+        assert_eq!(settings.channels, vec!["slack", "bluesky"]);
+
+        let rules: Vec<String> = settings
+            .rule
+            .unwrap()
+            .into_iter()
+            .map(|r| r.source)
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "slack:exclude:state=archived",
+                "bluesky:include:tag=Rezoning"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_key() -> Result<()> {
+        let config = Config::parse(
+            r#"
+            [schedule]
+            quiet_hours = "10pm-7am"
+
+            [channels.telegram]
+            bot_token = { env = "TELEGRAM_BOT_TOKEN" }
+            chat_ids = []
+
+            [channels.ntfy]
+            topics = ["kits:colour=red"]
+            rules = ["include:tag=Rezoning", "sometimes:tag=Rezoning"]
+            "#,
+        )?;
+
+        let errors = config.resolve_with(env).unwrap_err().to_string();
+        let errors: Vec<&str> = errors.lines().collect();
+        assert_eq!(errors.len(), 5);
+        assert!(errors[0].starts_with("schedule.quiet_hours: expected a time like 22:00"));
+        assert_eq!(errors[1], "channels.telegram.chat_ids: must not be empty");
+        assert_eq!(
+            errors[2],
+            "channels.telegram.bot_token: environment variable TELEGRAM_BOT_TOKEN is not set"
+        );
+        assert!(errors[3].starts_with("channels.ntfy.topics[0]: "));
+        assert!(errors[4].starts_with("channels.ntfy.rules[1]: expected include or exclude"));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let error = Config::parse("[channels.slack]\nwebhook = \"https://hooks.slack.com/x\"\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `webhook`"), "{}", error);
+        assert!(error.contains("line 2"), "{}", error);
+    }
+}
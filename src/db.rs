@@ -1,7 +1,12 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use chrono::{DateTime, Utc};
 use rusqlite::{params, Connection};
-use std::ops::{Deref, DerefMut};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+};
 
 use crate::models::Project;
 
@@ -10,6 +15,168 @@ pub struct Token {
     pub jwt: String,
 }
 
+/// A reference to a Bluesky post
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostRef {
+    pub uri: String,
+    pub cid: String,
+}
+
+/// The Bluesky thread for a project: the original post and the most recent reply in it
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlueskyThread {
+    pub root: PostRef,
+    pub latest: PostRef,
+}
+
+/// A message posted by the Slack bot, plus the summary it was posted with so the message can
+/// be re-rendered when the project changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlackMessageRef {
+    pub channel: String,
+    pub ts: String,
+    pub tweet: String,
+}
+
+/// A downloaded image, kept so retries don't have to download it again
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedImage {
+    pub etag: Option<String>,
+    pub content_type: String,
+    pub bytes: Vec<u8>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedEntryKind {
+    New,
+    Changed,
+}
+
+impl FeedEntryKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            FeedEntryKind::New => "new",
+            FeedEntryKind::Changed => "changed",
+        }
+    }
+}
+
+/// Something that happened to a project, as shown in the RSS/Atom feeds. The project is stored
+/// as it was at the time.
+#[derive(Debug, Clone)]
+pub struct FeedEntry {
+    pub id: i64,
+    pub kind: FeedEntryKind,
+    pub project: Project,
+    /// The LLM summary for new projects, or a description of what changed
+    pub summary: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The database used when --db isn't given
+pub const DEFAULT_DB_NAME: &str = "rezoning_scraper";
+
+/// Works out which file `--db` means. A bare name like `staging` is a database in the data
+/// directory (`~/.local/share/rezoning-scraper/staging.db` on Linux); anything with a slash or an
+/// extension is a path. Without `--db`, a `rezoning_scraper.db` in the working directory is still
+/// used if there is one, since that's where older versions kept it.
+pub fn resolve_path(db: Option<&str>) -> Result<PathBuf> {
+    let legacy = PathBuf::from(format!("{}.db", DEFAULT_DB_NAME));
+    if db.is_none() && legacy.exists() {
+        return Ok(legacy);
+    }
+    resolve_path_in(db, dirs::data_dir())
+}
+
+fn resolve_path_in(db: Option<&str>, data_dir: Option<PathBuf>) -> Result<PathBuf> {
+    let name = db.unwrap_or(DEFAULT_DB_NAME);
+    if name.contains(['/', std::path::MAIN_SEPARATOR]) || Path::new(name).extension().is_some() {
+        return Ok(PathBuf::from(name));
+    }
+    let data_dir = data_dir.ok_or_else(|| {
+        anyhow!("Couldn't find a data directory for the database; pass --db a path")
+    })?;
+    Ok(data_dir
+        .join("rezoning-scraper")
+        .join(format!("{}.db", name)))
+}
+
+/// An exclusive lock on a database, held until it's dropped. A run takes it before touching the
+/// database so two overlapping cron runs can't both queue and post the same projects. The OS
+/// releases it when the process exits, so a crashed run never leaves a stale lock behind.
+pub struct DatabaseLock {
+    _file: File,
+}
+
+/// The run holding a database's lock, as recorded in the lock file
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub since: DateTime<Utc>,
+    /// What's running, e.g. "daemon"
+    pub command: String,
+}
+
+fn lock_path(db_path: &Path) -> PathBuf {
+    let mut path = db_path.as_os_str().to_owned();
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+impl DatabaseLock {
+    /// Locks `<db>.lock` next to the database, or returns None if another run holds it. `command`
+    /// is recorded for the other run's error message.
+    pub fn try_acquire(db_path: &Path, command: &str) -> Result<Option<Self>> {
+        let path = lock_path(db_path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Couldn't create {}", parent.display()))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Couldn't open lock file {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Couldn't lock {}", path.display()))
+            }
+        }
+
+        // only truncate once we hold the lock, so we don't wipe out the holder's details
+        file.set_len(0)?;
+        writeln!(
+            file,
+            "{}\n{}\n{}",
+            std::process::id(),
+            Utc::now().to_rfc3339(),
+            command
+        )?;
+        Ok(Some(DatabaseLock { _file: file }))
+    }
+}
+
+impl LockHolder {
+    /// Who holds the database's lock, if the lock file says
+    pub fn read(db_path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(lock_path(db_path)).ok()?;
+        let mut lines = contents.lines();
+        let pid = lines.next()?.parse().ok()?;
+        let since = DateTime::parse_from_rfc3339(lines.next()?).ok()?.to_utc();
+        let command = lines.next().unwrap_or("run").to_string();
+        Some(LockHolder {
+            pid,
+            since,
+            command,
+        })
+    }
+}
+
 pub struct Database {
     conn: Connection,
 }
@@ -38,7 +205,12 @@ impl Database {
         Ok(db)
     }
 
-    pub fn new_from_file(path: &str) -> Result<Self> {
+    pub fn new_from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Couldn't create {}", parent.display()))?;
+        }
         let conn = Connection::open(path)?;
         conn.pragma_update(None, "synchronous", "NORMAL")?;
         let db = Database { conn };
@@ -74,6 +246,76 @@ impl Database {
             [],
         )?;
 
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS SlackMessages(
+                ProjectId TEXT PRIMARY KEY NOT NULL,
+                Channel TEXT NOT NULL,
+                Ts TEXT NOT NULL,
+                Tweet TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS RemovedProjects(
+                Id TEXT PRIMARY KEY NOT NULL,
+                RemovedAt INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS FeedEntries(
+                Id INTEGER PRIMARY KEY AUTOINCREMENT,
+                ProjectId TEXT NOT NULL,
+                Kind TEXT NOT NULL,
+                Serialized TEXT NOT NULL,
+                Summary TEXT NOT NULL,
+                CreatedAt INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS EmailDigests(
+                Address TEXT PRIMARY KEY NOT NULL,
+                LastSent INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS ImageCache(
+                Url TEXT PRIMARY KEY NOT NULL,
+                ETag TEXT,
+                ContentType TEXT NOT NULL,
+                Data BLOB NOT NULL,
+                FetchedAt INTEGER NOT NULL
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS ImageHashes(
+                ProjectId TEXT NOT NULL,
+                Url TEXT NOT NULL,
+                Hash INTEGER NOT NULL,
+                PRIMARY KEY(ProjectId, Url)
+            )",
+            [],
+        )?;
+
+        self.conn.execute(
+            "CREATE TABLE IF NOT EXISTS BlueskyPosts(
+                ProjectId TEXT PRIMARY KEY NOT NULL,
+                RootUri TEXT NOT NULL,
+                RootCid TEXT NOT NULL,
+                LatestUri TEXT NOT NULL,
+                LatestCid TEXT NOT NULL
+            )",
+            [],
+        )?;
+
         Ok(())
     }
 
@@ -86,7 +328,6 @@ impl Database {
         Ok(count > 0)
     }
 
-    #[allow(dead_code)]
     pub fn get_projects(&self) -> Result<Vec<Project>> {
         let mut stmt = self.conn.prepare("SELECT Serialized FROM Projects")?;
         let rows = stmt.query_map([], |row| row.get(0))?;
@@ -126,6 +367,30 @@ impl Database {
         Ok(())
     }
 
+    /// Whether a project has disappeared from the API. Removed projects stay in the Projects table.
+    pub fn is_removed(&self, id: &str) -> Result<bool> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM RemovedProjects WHERE Id = ?",
+            params![id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn set_removed(&self, id: &str, removed: bool) -> Result<()> {
+        if removed {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO RemovedProjects(Id, RemovedAt) VALUES(?1, ?2)",
+                params![id, Utc::now().timestamp()],
+            )?;
+        } else {
+            self.conn
+                .execute("DELETE FROM RemovedProjects WHERE Id = ?", params![id])?;
+        }
+
+        Ok(())
+    }
+
     pub fn get_token(&self) -> Result<Option<Token>> {
         let result = self.conn.query_row(
             "SELECT Expiration, Token FROM TokenCache LIMIT 1",
@@ -184,6 +449,294 @@ impl Database {
         Ok(count == 0)
     }
 
+    pub fn get_bluesky_thread(&self, project_id: &str) -> Result<Option<BlueskyThread>> {
+        let result = self.conn.query_row(
+            "SELECT RootUri, RootCid, LatestUri, LatestCid FROM BlueskyPosts WHERE ProjectId = ?",
+            params![project_id],
+            |row| {
+                Ok(BlueskyThread {
+                    root: PostRef {
+                        uri: row.get(0)?,
+                        cid: row.get(1)?,
+                    },
+                    latest: PostRef {
+                        uri: row.get(2)?,
+                        cid: row.get(3)?,
+                    },
+                })
+            },
+        );
+
+        match result {
+            Ok(thread) => Ok(Some(thread)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records a post made about a project. The first post becomes the root of the thread,
+    /// later ones become the parent for the next reply.
+    pub fn record_bluesky_post(&self, project_id: &str, post: &PostRef) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO BlueskyPosts(ProjectId, RootUri, RootCid, LatestUri, LatestCid)
+             VALUES(?1, ?2, ?3, ?2, ?3)
+             ON CONFLICT(ProjectId) DO UPDATE SET
+                LatestUri = excluded.LatestUri, LatestCid = excluded.LatestCid",
+            params![project_id, post.uri, post.cid],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_slack_message(&self, project_id: &str) -> Result<Option<SlackMessageRef>> {
+        let result = self.conn.query_row(
+            "SELECT Channel, Ts, Tweet FROM SlackMessages WHERE ProjectId = ?",
+            params![project_id],
+            |row| {
+                Ok(SlackMessageRef {
+                    channel: row.get(0)?,
+                    ts: row.get(1)?,
+                    tweet: row.get(2)?,
+                })
+            },
+        );
+
+        match result {
+            Ok(message) => Ok(Some(message)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn record_slack_message(&self, project_id: &str, message: &SlackMessageRef) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO SlackMessages(ProjectId, Channel, Ts, Tweet) VALUES(?1, ?2, ?3, ?4)
+             ON CONFLICT(ProjectId) DO UPDATE SET
+                Channel = excluded.Channel, Ts = excluded.Ts, Tweet = excluded.Tweet",
+            params![project_id, message.channel, message.ts, message.tweet],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn record_feed_entry(
+        &self,
+        kind: FeedEntryKind,
+        project: &Project,
+        summary: &str,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO FeedEntries(ProjectId, Kind, Serialized, Summary, CreatedAt)
+             VALUES(?1, ?2, ?3, ?4, ?5)",
+            params![
+                project.id,
+                kind.as_str(),
+                serde_json::to_string(project)?,
+                summary,
+                Utc::now().timestamp()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Feed entries, newest first: at most `limit` of them (all if None), and only projects
+    /// tagged `tag` (case-insensitively) if given
+    pub fn get_feed_entries(
+        &self,
+        tag: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<Vec<FeedEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT Id, Kind, Serialized, Summary, CreatedAt FROM FeedEntries
+             WHERE ?1 IS NULL OR EXISTS (
+                 SELECT 1 FROM json_each(Serialized, '$.attributes.\"project-tag-list\"')
+                 WHERE lower(value) = lower(?1)
+             )
+             ORDER BY Id DESC
+             LIMIT ?2",
+        )?;
+        // a negative LIMIT means no limit
+        let limit = limit.map_or(-1, |l| l as i64);
+        let rows = stmt.query_map(params![tag, limit], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (id, kind, json, summary, created_at) = row?;
+            entries.push(FeedEntry {
+                id,
+                kind: if kind == FeedEntryKind::Changed.as_str() {
+                    FeedEntryKind::Changed
+                } else {
+                    FeedEntryKind::New
+                },
+                project: serde_json::from_str(&json)?,
+                summary,
+                created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_default(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    pub fn get_last_digest(&self, address: &str) -> Result<Option<DateTime<Utc>>> {
+        let result = self.conn.query_row(
+            "SELECT LastSent FROM EmailDigests WHERE Address = ?",
+            params![address],
+            |row| row.get::<_, i64>(0),
+        );
+
+        match result {
+            Ok(timestamp) => Ok(DateTime::from_timestamp(timestamp, 0)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set_last_digest(&self, address: &str, sent: DateTime<Utc>) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO EmailDigests(Address, LastSent) VALUES(?1, ?2)
+             ON CONFLICT(Address) DO UPDATE SET LastSent = excluded.LastSent",
+            params![address, sent.timestamp()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Everyone we've sent a digest to
+    pub fn get_digest_addresses(&self) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare("SELECT Address FROM EmailDigests")?;
+        let addresses = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(addresses)
+    }
+
+    pub fn delete_last_digest(&self, address: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM EmailDigests WHERE Address = ?",
+            params![address],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_cached_image(&self, url: &str) -> Result<Option<CachedImage>> {
+        let result = self.conn.query_row(
+            "SELECT ETag, ContentType, Data, FetchedAt FROM ImageCache WHERE Url = ?",
+            params![url],
+            |row| {
+                Ok(CachedImage {
+                    etag: row.get(0)?,
+                    content_type: row.get(1)?,
+                    bytes: row.get(2)?,
+                    fetched_at: DateTime::from_timestamp(row.get(3)?, 0).unwrap_or_default(),
+                })
+            },
+        );
+
+        match result {
+            Ok(image) => Ok(Some(image)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn cache_image(&self, url: &str, image: &CachedImage) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO ImageCache(Url, ETag, ContentType, Data, FetchedAt) VALUES(?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(Url) DO UPDATE SET ETag = excluded.ETag, ContentType = excluded.ContentType,
+                Data = excluded.Data, FetchedAt = excluded.FetchedAt",
+            params![
+                url,
+                image.etag,
+                image.content_type,
+                image.bytes,
+                image.fetched_at.timestamp()
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Marks a cached image as fresh, e.g. after the server said it hasn't changed
+    pub fn touch_cached_image(&self, url: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE ImageCache SET FetchedAt = ?1 WHERE Url = ?2",
+            params![Utc::now().timestamp(), url],
+        )?;
+
+        Ok(())
+    }
+
+    /// Drops images fetched before `before`, then the oldest images until the rest fit in
+    /// `max_bytes`. Returns how many were removed.
+    pub fn prune_image_cache(&self, before: DateTime<Utc>, max_bytes: usize) -> Result<usize> {
+        let expired = self.conn.execute(
+            "DELETE FROM ImageCache WHERE FetchedAt < ?",
+            params![before.timestamp()],
+        )?;
+        let over_size = self.conn.execute(
+            "DELETE FROM ImageCache WHERE Url IN (
+                SELECT Url FROM (
+                    SELECT Url, SUM(length(Data)) OVER (ORDER BY FetchedAt DESC, Url) AS Total
+                    FROM ImageCache
+                )
+                WHERE Total > ?
+            )",
+            params![max_bytes as i64],
+        )?;
+
+        Ok(expired + over_size)
+    }
+
+    pub fn record_image_hash(&self, project_id: &str, url: &str, hash: u64) -> Result<()> {
+        // SQLite integers are signed, so the hash is stored as its i64 bit pattern
+        self.conn.execute(
+            "INSERT INTO ImageHashes(ProjectId, Url, Hash) VALUES(?1, ?2, ?3)
+             ON CONFLICT(ProjectId, Url) DO UPDATE SET Hash = excluded.Hash",
+            params![project_id, url, hash as i64],
+        )?;
+
+        Ok(())
+    }
+
+    /// Keeps only the `keep` newest image hashes. Returns how many were removed.
+    pub fn prune_image_hashes(&self, keep: usize) -> Result<usize> {
+        let removed = self.conn.execute(
+            "DELETE FROM ImageHashes WHERE rowid NOT IN (
+                SELECT rowid FROM ImageHashes ORDER BY rowid DESC LIMIT ?
+            )",
+            params![keep as i64],
+        )?;
+
+        Ok(removed)
+    }
+
+    /// Returns (project ID, hash) for every image we've seen
+    pub fn get_image_hashes(&self) -> Result<Vec<(String, u64)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT ProjectId, Hash FROM ImageHashes")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+        })?;
+
+        let mut hashes = Vec::new();
+        for row in rows {
+            hashes.push(row?);
+        }
+
+        Ok(hashes)
+    }
+
     pub fn cache_response(&self, url: &str, value: &str) -> Result<()> {
         let expiration = Utc::now().timestamp() + 3600; // 1 hour from now
 
@@ -208,6 +761,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_resolve_path() -> Result<()> {
+        let data_dir = Some(PathBuf::from("/data"));
+        assert_eq!(
+            resolve_path_in(None, data_dir.clone())?,
+            PathBuf::from("/data/rezoning-scraper/rezoning_scraper.db")
+        );
+        assert_eq!(
+            resolve_path_in(Some("staging"), data_dir.clone())?,
+            PathBuf::from("/data/rezoning-scraper/staging.db")
+        );
+        assert_eq!(
+            resolve_path_in(Some("test.db"), data_dir.clone())?,
+            PathBuf::from("test.db")
+        );
+        assert_eq!(
+            resolve_path_in(Some("/var/lib/scraper/prod"), None)?,
+            PathBuf::from("/var/lib/scraper/prod")
+        );
+        assert!(resolve_path_in(Some("staging"), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_db_and_lock() -> Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("rezoning-scraper-test-{}", std::process::id()));
+        let path = dir.join("nested").join("test.db");
+
+        // parent directories are created
+        let _db = Database::new_from_file(&path)?;
+        assert!(path.exists());
+
+        let lock = DatabaseLock::try_acquire(&path, "daemon")?.unwrap();
+        assert!(DatabaseLock::try_acquire(&path, "run")?.is_none());
+        let holder = LockHolder::read(&path).unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert_eq!(holder.command, "daemon");
+        assert!(Utc::now() - holder.since < chrono::Duration::minutes(1));
+
+        drop(lock);
+        assert!(DatabaseLock::try_acquire(&path, "run")?.is_some());
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
     #[test]
     fn test_contains_works() -> Result<()> {
         let mut db = Database::new_in_memory()?;
@@ -216,10 +816,7 @@ mod tests {
 
         let project = Project {
             id: "foo".to_string(),
-            project_type: "".to_string(),
-            attributes: Default::default(),
-            relationships: Default::default(),
-            links: Default::default(),
+            ..Default::default()
         };
 
         db.upsert_projects(&[project])?;
@@ -235,12 +832,10 @@ mod tests {
         let project1 = Project {
             id: "foo".to_string(),
             project_type: "first".to_string(),
-            attributes: Default::default(),
-            relationships: Default::default(),
-            links: Default::default(),
+            ..Default::default()
         };
 
-        db.upsert_projects(&[project1.clone()])?;
+        db.upsert_projects(std::slice::from_ref(&project1))?;
         let retrieved = db.get_project("foo")?;
         assert_eq!(retrieved.project_type, "first");
 
@@ -249,13 +844,186 @@ mod tests {
             ..project1
         };
 
-        db.upsert_projects(&[project2.clone()])?;
+        db.upsert_projects(std::slice::from_ref(&project2))?;
         let retrieved = db.get_project("foo")?;
         assert_eq!(retrieved.project_type, "second");
 
         Ok(())
     }
 
+    #[test]
+    fn test_bluesky_thread_works() -> Result<()> {
+        let db = Database::new_in_memory()?;
+
+        assert!(db.get_bluesky_thread("foo")?.is_none());
+
+        let root = PostRef {
+            uri: "at://did:plc:abc/app.bsky.feed.post/1".to_string(),
+            cid: "cid1".to_string(),
+        };
+        db.record_bluesky_post("foo", &root)?;
+
+        let thread = db.get_bluesky_thread("foo")?.unwrap();
+        assert_eq!(thread.root, root);
+        assert_eq!(thread.latest, root);
+
+        let reply = PostRef {
+            uri: "at://did:plc:abc/app.bsky.feed.post/2".to_string(),
+            cid: "cid2".to_string(),
+        };
+        db.record_bluesky_post("foo", &reply)?;
+
+        let thread = db.get_bluesky_thread("foo")?.unwrap();
+        assert_eq!(thread.root, root);
+        assert_eq!(thread.latest, reply);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slack_messages_work() -> Result<()> {
+        let db = Database::new_in_memory()?;
+
+        assert!(db.get_slack_message("foo")?.is_none());
+
+        let message = SlackMessageRef {
+            channel: "C123".to_string(),
+            ts: "1700000000.000100".to_string(),
+            tweet: "123 Main St. 6 storeys".to_string(),
+        };
+        db.record_slack_message("foo", &message)?;
+        assert_eq!(db.get_slack_message("foo")?.unwrap(), message);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_removed_projects_work() -> Result<()> {
+        let db = Database::new_in_memory()?;
+
+        assert!(!db.is_removed("foo")?);
+        db.set_removed("foo", true)?;
+        db.set_removed("foo", true)?;
+        assert!(db.is_removed("foo")?);
+        db.set_removed("foo", false)?;
+        assert!(!db.is_removed("foo")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_feed_entries_work() -> Result<()> {
+        let db = Database::new_in_memory()?;
+        let project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+
+        db.record_feed_entry(FeedEntryKind::New, &project, "a new project")?;
+        db.record_feed_entry(FeedEntryKind::Changed, &project, "description was revised")?;
+
+        let entries = db.get_feed_entries(None, None)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].kind, FeedEntryKind::Changed);
+        assert_eq!(entries[0].summary, "description was revised");
+        assert_eq!(entries[1].kind, FeedEntryKind::New);
+        assert_eq!(entries[1].project.id, "foo");
+
+        assert_eq!(db.get_feed_entries(None, Some(1))?.len(), 1);
+
+        let mut tagged = project.clone();
+        tagged.attributes.project_tag_list = vec!["Rezoning".to_string()];
+        db.record_feed_entry(FeedEntryKind::New, &tagged, "a rezoning")?;
+        let entries = db.get_feed_entries(Some("rezoning"), None)?;
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].summary, "a rezoning");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_digest_works() -> Result<()> {
+        let db = Database::new_in_memory()?;
+
+        assert!(db.get_last_digest("a@example.com")?.is_none());
+
+        let sent = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        db.set_last_digest("a@example.com", sent)?;
+        assert_eq!(db.get_last_digest("a@example.com")?, Some(sent));
+        assert_eq!(db.get_digest_addresses()?, vec!["a@example.com"]);
+
+        db.delete_last_digest("a@example.com")?;
+        assert!(db.get_last_digest("a@example.com")?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_cache_works() -> Result<()> {
+        let db = Database::new_in_memory()?;
+        let url = "https://example.com/a.jpg";
+
+        assert!(db.get_cached_image(url)?.is_none());
+
+        let image = CachedImage {
+            etag: Some("\"abc\"".to_string()),
+            content_type: "image/jpeg".to_string(),
+            bytes: vec![1, 2, 3],
+            fetched_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        };
+        db.cache_image(url, &image)?;
+        assert_eq!(db.get_cached_image(url)?.unwrap(), image);
+
+        db.touch_cached_image(url)?;
+        let touched = db.get_cached_image(url)?.unwrap();
+        assert!(touched.fetched_at > image.fetched_at);
+        assert_eq!(touched.bytes, image.bytes);
+
+        // an old image goes by age, then the oldest of the rest go until they fit
+        let cached = |days_ago: i64, len: usize| CachedImage {
+            bytes: vec![0; len],
+            fetched_at: Utc::now() - chrono::Duration::days(days_ago),
+            ..image.clone()
+        };
+        db.cache_image("https://example.com/old.jpg", &cached(60, 1))?;
+        db.cache_image("https://example.com/b.jpg", &cached(2, 5))?;
+        db.cache_image("https://example.com/c.jpg", &cached(1, 5))?;
+        let removed = db.prune_image_cache(Utc::now() - chrono::Duration::days(30), 10)?;
+        assert_eq!(removed, 2);
+        assert!(db
+            .get_cached_image("https://example.com/old.jpg")?
+            .is_none());
+        assert!(db.get_cached_image("https://example.com/b.jpg")?.is_none());
+        assert!(db.get_cached_image("https://example.com/c.jpg")?.is_some());
+        assert!(db.get_cached_image(url)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_hashes_work() -> Result<()> {
+        let db = Database::new_in_memory()?;
+
+        db.record_image_hash("foo", "https://example.com/a.jpg", u64::MAX)?;
+        db.record_image_hash("bar", "https://example.com/a.jpg", 1)?;
+        db.record_image_hash("bar", "https://example.com/a.jpg", 2)?;
+
+        let mut hashes = db.get_image_hashes()?;
+        hashes.sort();
+        assert_eq!(
+            hashes,
+            vec![("bar".to_string(), 2), ("foo".to_string(), u64::MAX)]
+        );
+
+        db.record_image_hash("baz", "https://example.com/b.jpg", 3)?;
+        assert_eq!(db.prune_image_hashes(2)?, 1);
+        let mut hashes = db.get_image_hashes()?;
+        hashes.sort();
+        assert_eq!(hashes, vec![("bar".to_string(), 2), ("baz".to_string(), 3)]);
+
+        Ok(())
+    }
+
     #[test]
     fn test_token_works() -> Result<()> {
         let mut db = Database::new_in_memory()?;
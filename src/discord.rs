@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use reqwest::{header::HeaderMap, StatusCode};
+use serde_json::{json, Value};
+use tokio::time::sleep;
+
+use crate::{
+    db::Database, images::usable_image_url, models::SummarizedProject, notifier::Notifier,
+    text::truncate,
+};
+
+// Discord's embed limits
+const MAX_TITLE_LENGTH: usize = 256;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+
+const REZONING_COLOR: u32 = 0xE67E22;
+const DEVELOPMENT_COLOR: u32 = 0x3498DB;
+const DEFAULT_COLOR: u32 = 0x95A5A6;
+
+// How many times to wait out a 429 before giving up and letting the queue retry later
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+// Don't stall the whole run on a long (or bogus) wait; fail the message and let the queue retry it
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+pub async fn post_to_discord(
+    client: &reqwest::Client,
+    webhook_url: &str,
+    project: &SummarizedProject,
+) -> Result<()> {
+    println!("{}", "Posting to Discord...".bold().cyan());
+
+    let message = create_discord_message(project);
+    let mut retries = 0;
+
+    loop {
+        // wait=true makes Discord validate the message before responding, so errors surface here
+        let response = client
+            .post(webhook_url)
+            .query(&[("wait", "true")])
+            .json(&message)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            retries += 1;
+            if retries > MAX_RATE_LIMIT_RETRIES {
+                bail!(
+                    "Discord rate limit still exceeded after {} retries",
+                    retries - 1
+                );
+            }
+
+            let wait = retry_after(response.headers()).unwrap_or(Duration::from_secs(1));
+            if wait > MAX_RATE_LIMIT_WAIT {
+                bail!(
+                    "Discord rate limited us for {}s; leaving the message for the next run",
+                    wait.as_secs()
+                );
+            }
+            eprintln!("Rate limited by Discord; waiting {}ms", wait.as_millis());
+            sleep(wait).await;
+            continue;
+        }
+
+        let headers = response.headers().clone();
+        response.error_for_status()?;
+
+        // Don't use up the last request in the bucket and then get a 429 on the next message
+        if let Some(wait) = bucket_reset_wait(&headers) {
+            sleep(wait.min(MAX_RATE_LIMIT_WAIT)).await;
+        }
+
+        break;
+    }
+
+    println!("{}", "Posted message to Discord".green());
+    Ok(())
+}
+
+pub fn create_discord_message(project: &SummarizedProject) -> Value {
+    let SummarizedProject { project, tweet } = project;
+    let attributes = &project.attributes;
+
+    let color = match project.post_prefix() {
+        Some("Rezoning") => REZONING_COLOR,
+        Some("DP") => DEVELOPMENT_COLOR,
+        _ => DEFAULT_COLOR,
+    };
+
+    let mut embed = json!({
+        "title": truncate(attributes.name.replace('\n', "").trim(), MAX_TITLE_LENGTH),
+        "url": project.links.self_link,
+        "description": truncate(tweet, MAX_DESCRIPTION_LENGTH),
+        "color": color
+    });
+
+    if let Some(image_url) = usable_image_url(&attributes.image_url) {
+        embed["thumbnail"] = json!({ "url": image_url });
+    }
+
+    if !attributes.project_tag_list.is_empty() {
+        embed["footer"] = json!({ "text": attributes.project_tag_list.join(" · ") });
+    }
+
+    if let Some(published_at) = &attributes.published_at {
+        embed["timestamp"] = json!(published_at);
+    }
+
+    json!({
+        "embeds": [embed],
+        // never ping anyone, whatever ends up in the text
+        "allowed_mentions": { "parse": [] }
+    })
+}
+
+/// How long a 429 response asks us to wait. Discord sends seconds, possibly fractional.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    header_seconds(headers, "retry-after")
+        .or_else(|| header_seconds(headers, "x-ratelimit-reset-after"))
+}
+
+/// If this response used up the rate limit bucket, how long until it refills
+fn bucket_reset_wait(headers: &HeaderMap) -> Option<Duration> {
+    let remaining = headers
+        .get("x-ratelimit-remaining")?
+        .to_str()
+        .ok()?
+        .parse::<u32>()
+        .ok()?;
+
+    if remaining == 0 {
+        header_seconds(headers, "x-ratelimit-reset-after")
+    } else {
+        None
+    }
+}
+
+fn header_seconds(headers: &HeaderMap, name: &str) -> Option<Duration> {
+    let seconds = headers.get(name)?.to_str().ok()?.parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+pub struct DiscordNotifier<'a> {
+    pub webhook_url: &'a str,
+    pub client: &'a reqwest::Client,
+}
+
+impl Notifier for DiscordNotifier<'_> {
+    type Message = SummarizedProject;
+
+    const NAME: &'static str = "Discord";
+    const QUEUE_NAME: &'static str = "discord_post_queue";
+
+    async fn send(&self, _db: &Database, project: &SummarizedProject) -> Result<()> {
+        post_to_discord(self.client, self.webhook_url, project).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Project;
+
+    fn summarized(tags: &[&str]) -> SummarizedProject {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = "123 Main St rezoning application\n".to_string();
+        project.attributes.project_tag_list = tags.iter().map(|t| t.to_string()).collect();
+        project.attributes.image_url = Some("https://example.com/a.jpg".to_string());
+        project.attributes.published_at = Some("2021-05-13T11:14:50-07:00".to_string());
+        project.links.self_link = "https://shapeyourcity.ca/123-main".to_string();
+
+        SummarizedProject {
+            project,
+            tweet: "123 Main St. 6 storeys, 40 units".to_string(),
+        }
+    }
+
+    #[test]
+    fn builds_embed() {
+        let message = create_discord_message(&summarized(&["Rezoning", "Kitsilano"]));
+
+        assert_eq!(
+            message,
+            json!({
+                "embeds": [{
+                    "title": "123 Main St rezoning application",
+                    "url": "https://shapeyourcity.ca/123-main",
+                    "description": "123 Main St. 6 storeys, 40 units",
+                    "color": REZONING_COLOR,
+                    "thumbnail": { "url": "https://example.com/a.jpg" },
+                    "footer": { "text": "Rezoning · Kitsilano" },
+                    "timestamp": "2021-05-13T11:14:50-07:00"
+                }],
+                "allowed_mentions": { "parse": [] }
+            })
+        );
+    }
+
+    #[test]
+    fn colors_by_tag() {
+        let color =
+            |tags: &[&str]| create_discord_message(&summarized(tags))["embeds"][0]["color"].clone();
+        assert_eq!(color(&["Development"]), json!(DEVELOPMENT_COLOR));
+        assert_eq!(color(&[]), json!(DEFAULT_COLOR));
+    }
+
+    #[test]
+    fn reads_rate_limit_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("retry-after", "1.5".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_millis(1500)));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+        headers.insert("x-ratelimit-reset-after", "2".parse().unwrap());
+        assert_eq!(bucket_reset_wait(&headers), Some(Duration::from_secs(2)));
+
+        headers.insert("x-ratelimit-remaining", "4".parse().unwrap());
+        assert_eq!(bucket_reset_wait(&headers), None);
+    }
+}
@@ -0,0 +1,600 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use lettre::{
+    message::MultiPart,
+    transport::smtp::{
+        authentication::Credentials,
+        client::{Tls, TlsParameters},
+        extension::ClientId,
+    },
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::Database,
+    models::{Project, ProjectUpdate, SummarizedProject},
+    queue::{self, Queue},
+    text::escape_html,
+};
+
+const SMTP_TIMEOUT: Duration = Duration::from_secs(60);
+
+const QUEUE_PREFIX: &str = "email_digest:";
+
+// Runs usually happen a few minutes apart, so don't let a daily digest creep later every day
+const DAILY_DIGEST_INTERVAL: chrono::Duration = chrono::Duration::hours(23);
+
+/// Something that goes into a subscriber's next digest
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum DigestItem {
+    New(SummarizedProject),
+    Changed(ProjectUpdate),
+}
+
+impl DigestItem {
+    fn project(&self) -> &Project {
+        match self {
+            DigestItem::New(summarized) => &summarized.project,
+            DigestItem::Changed(update) => &update.project,
+        }
+    }
+}
+
+/// How often subscribers get a digest
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DigestSchedule {
+    // Every run that found something
+    PerRun,
+    // At most once a day
+    Daily,
+}
+
+impl DigestSchedule {
+    pub fn is_due(self, last_sent: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        match (self, last_sent) {
+            (DigestSchedule::PerRun, _) | (DigestSchedule::Daily, None) => true,
+            (DigestSchedule::Daily, Some(last_sent)) => now - last_sent >= DAILY_DIGEST_INTERVAL,
+        }
+    }
+}
+
+/// An email address and the tags they care about. No tags means everything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscriber {
+    pub address: String,
+    pub tags: Vec<String>,
+}
+
+impl Subscriber {
+    pub fn wants(&self, project: &Project) -> bool {
+        let project_tags = &project.attributes.project_tag_list;
+        self.tags.is_empty()
+            || self
+                .tags
+                .iter()
+                .any(|tag| project_tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+    }
+
+    /// Each subscriber has their own queue so a failed send to one doesn't hold up the others
+    pub fn queue(&self, conn: &Connection) -> Queue<DigestItem> {
+        Queue::new(&format!("{}{}", QUEUE_PREFIX, self.address), conn)
+    }
+}
+
+/// Drops the queued items and digest history of addresses that are no longer subscribed
+pub fn forget_unsubscribed(db: &Database, subscribers: &[Subscriber]) -> Result<()> {
+    let mut addresses = db.get_digest_addresses()?;
+    for name in queue::names_with_prefix(db, QUEUE_PREFIX)? {
+        addresses.push(name[QUEUE_PREFIX.len()..].to_string());
+    }
+    addresses.sort();
+    addresses.dedup();
+
+    for address in addresses {
+        if subscribers.iter().any(|s| s.address == address) {
+            continue;
+        }
+        println!("Forgetting unsubscribed address {}", address);
+        let subscriber = Subscriber {
+            address,
+            tags: vec![],
+        };
+        subscriber.queue(db).clear(db)?;
+        db.delete_last_digest(&subscriber.address)?;
+    }
+
+    Ok(())
+}
+
+/// Parses an `--email-subscriber` value like `alice@example.com` or
+/// `bob@example.com=Rezoning|Development`
+pub fn parse_subscriber(value: &str) -> Result<Subscriber, String> {
+    let (address, tags) = match value.split_once('=') {
+        Some((address, tags)) => (address.trim(), tags),
+        None => (value.trim(), ""),
+    };
+
+    if address.contains(['<', '>', ' ', '\r', '\n'])
+        || !address
+            .split_once('@')
+            .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+    {
+        return Err(format!("expected an email address, got {:?}", address));
+    }
+
+    Ok(Subscriber {
+        address: address.to_string(),
+        tags: tags
+            .split('|')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(String::from)
+            .collect(),
+    })
+}
+
+/// Adds an item to the queue of every subscriber who wants it
+pub fn enqueue(conn: &Connection, subscribers: &[Subscriber], item: &DigestItem) -> Result<()> {
+    for subscriber in subscribers {
+        if subscriber.wants(item.project()) {
+            subscriber.queue(conn).push(conn, item.clone())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Where and how to connect to the SMTP server
+#[derive(Debug, Clone)]
+pub struct SmtpConfig {
+    host: String,
+    port: u16,
+    /// TLS from the start (smtps://) rather than upgrading with STARTTLS
+    implicit_tls: bool,
+    credentials: Option<(String, String)>,
+}
+
+impl SmtpConfig {
+    /// `smtp://host[:port]` uses STARTTLS when the server offers it, which local test sinks
+    /// usually don't. `smtps://host[:port]` connects with TLS.
+    pub fn new(url: &str, username: Option<&str>, password: Option<&str>) -> Result<Self> {
+        let parsed =
+            reqwest::Url::parse(url).map_err(|e| anyhow!("Invalid SMTP URL {}: {}", url, e))?;
+
+        let implicit_tls = match parsed.scheme() {
+            "smtp" => false,
+            "smtps" => true,
+            scheme => bail!("SMTP URL must be smtp:// or smtps://, got {}://", scheme),
+        };
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| anyhow!("SMTP URL is missing a host: {}", url))?
+            .to_string();
+        let port = parsed
+            .port()
+            .unwrap_or(if implicit_tls { 465 } else { 587 });
+
+        let credentials = match (username, password) {
+            (Some(username), Some(password)) => Some((username.to_string(), password.to_string())),
+            (None, None) => None,
+            _ => bail!("SMTP username and password must be given together"),
+        };
+
+        Ok(SmtpConfig {
+            host,
+            port,
+            implicit_tls,
+            credentials,
+        })
+    }
+
+    fn transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
+        let parameters = TlsParameters::new(self.host.clone())?;
+        let tls = if self.implicit_tls {
+            Tls::Wrapper(parameters)
+        } else if self.credentials.is_some() {
+            // never send the password in plain text
+            Tls::Required(parameters)
+        } else {
+            Tls::Opportunistic(parameters)
+        };
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&self.host)
+            .port(self.port)
+            .hello_name(ClientId::Domain("localhost".to_string()))
+            .tls(tls)
+            .timeout(Some(SMTP_TIMEOUT));
+        if let Some((username, password)) = &self.credentials {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(builder.build())
+    }
+}
+
+/// The subject and bodies of one digest email
+#[derive(Debug)]
+pub struct Digest {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+pub fn build_digest(items: &[DigestItem]) -> Digest {
+    let new: Vec<&SummarizedProject> = items
+        .iter()
+        .filter_map(|item| match item {
+            DigestItem::New(summarized) => Some(summarized),
+            DigestItem::Changed(_) => None,
+        })
+        .collect();
+    let changed: Vec<&ProjectUpdate> = items
+        .iter()
+        .filter_map(|item| match item {
+            DigestItem::Changed(update) => Some(update),
+            DigestItem::New(_) => None,
+        })
+        .collect();
+
+    let mut counts = Vec::new();
+    if !new.is_empty() {
+        counts.push(plural(new.len(), "new project", "new projects"));
+    }
+    if !changed.is_empty() {
+        counts.push(plural(changed.len(), "update", "updates"));
+    }
+    let subject = format!("ShapeYourCity digest: {}", counts.join(", "));
+
+    let mut text = String::new();
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<body style=\"font-family: sans-serif; max-width: 640px;\">\n",
+    );
+
+    if !new.is_empty() {
+        text.push_str("New projects\n============\n\n");
+        html.push_str("<h2>New projects</h2>\n");
+
+        for SummarizedProject { project, tweet } in new {
+            let title = project.display_title();
+            let link = &project.links.self_link;
+            text.push_str(&format!("{}\n{}\n{}\n\n", title, tweet, link));
+            html.push_str(&format!(
+                "<h3><a href=\"{}\">{}</a></h3>\n<p>{}</p>\n",
+                escape_html(link),
+                escape_html(&title),
+                escape_html(tweet)
+            ));
+        }
+    }
+
+    if !changed.is_empty() {
+        text.push_str("Updates\n=======\n\n");
+        html.push_str("<h2>Updates</h2>\n");
+
+        for update in changed {
+            let title = update.project.display_title();
+            let link = &update.project.links.self_link;
+            let changes = update.describe_changes();
+            text.push_str(&format!("{}\n{}\n{}\n\n", title, changes, link));
+            html.push_str(&format!(
+                "<h3><a href=\"{}\">{}</a></h3>\n<p>{}</p>\n",
+                escape_html(link),
+                escape_html(&title),
+                escape_html(&changes)
+            ));
+        }
+    }
+
+    html.push_str("</body>\n</html>\n");
+
+    Digest {
+        subject,
+        text: text.trim_end().to_string() + "\n",
+        html,
+    }
+}
+
+fn plural(count: usize, singular: &str, plural: &str) -> String {
+    format!("{} {}", count, if count == 1 { singular } else { plural })
+}
+
+/// Builds a multipart/alternative message. Clients show the last alternative they can render,
+/// so HTML goes last.
+pub fn format_message(
+    from: &str,
+    to: &str,
+    digest: &Digest,
+    now: DateTime<Utc>,
+) -> Result<Message> {
+    let mut hasher = DefaultHasher::new();
+    to.hash(&mut hasher);
+    let unique = format!("{}.{:x}", now.timestamp_micros(), hasher.finish());
+    let domain = from.rsplit_once('@').map(|(_, d)| d).unwrap_or("localhost");
+
+    let message = Message::builder()
+        .from(from.parse()?)
+        .to(to.parse()?)
+        .subject(&digest.subject)
+        .date(now.into())
+        .message_id(Some(format!("<{}@{}>", unique, domain)))
+        .multipart(MultiPart::alternative_plain_html(
+            digest.text.clone(),
+            digest.html.clone(),
+        ))?;
+
+    Ok(message)
+}
+
+pub async fn send_email(config: &SmtpConfig, message: Message) -> Result<()> {
+    let recipients = message.envelope().to().iter().map(|a| a.to_string());
+    let recipients = recipients.collect::<Vec<_>>().join(", ");
+    println!(
+        "{}",
+        format!("Emailing digest to {}...", recipients)
+            .bold()
+            .cyan()
+    );
+
+    config
+        .transport()?
+        .send(message)
+        .await
+        .map_err(|e| anyhow!("Couldn't send email via {}: {}", config.host, e))?;
+
+    println!("{}", "Sent digest".green());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    fn project(name: &str, tags: &[&str]) -> Project {
+        let mut project = Project {
+            id: name.to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = name.to_string();
+        project.attributes.project_tag_list = tags.iter().map(|t| t.to_string()).collect();
+        project.links.self_link = format!("https://shapeyourcity.ca/{}", name.replace(' ', "-"));
+        project
+    }
+
+    fn items() -> Vec<DigestItem> {
+        vec![
+            DigestItem::New(SummarizedProject {
+                project: project("123 Main St", &["Rezoning"]),
+                tweet: "6 storeys & 40 <rental> units".to_string(),
+            }),
+            DigestItem::Changed(ProjectUpdate {
+                project: project("456 Oak St", &["Development"]),
+                changes: vec![crate::models::ProjectChange {
+                    field: "state".to_string(),
+                    old_value: "published".to_string(),
+                    new_value: "archived".to_string(),
+                }],
+            }),
+        ]
+    }
+
+    #[test]
+    fn parses_subscribers() {
+        assert_eq!(
+            parse_subscriber("a@example.com"),
+            Ok(Subscriber {
+                address: "a@example.com".to_string(),
+                tags: vec![],
+            })
+        );
+        assert_eq!(
+            parse_subscriber("b@example.com=Rezoning| Development")
+                .unwrap()
+                .tags,
+            vec!["Rezoning", "Development"]
+        );
+        assert!(parse_subscriber("not an address").is_err());
+        assert!(parse_subscriber("@example.com").is_err());
+    }
+
+    #[test]
+    fn filters_by_tag() {
+        let everything = parse_subscriber("a@example.com").unwrap();
+        let rezonings = parse_subscriber("b@example.com=rezoning").unwrap();
+
+        let rezoning = project("123 Main St", &["Rezoning"]);
+        let development = project("456 Oak St", &["Development"]);
+
+        assert!(everything.wants(&rezoning) && everything.wants(&development));
+        assert!(rezonings.wants(&rezoning));
+        assert!(!rezonings.wants(&development));
+    }
+
+    #[test]
+    fn forgets_unsubscribed_addresses() -> Result<()> {
+        let db = Database::new_in_memory()?;
+        let kept = parse_subscriber("a@example.com").unwrap();
+        let removed = parse_subscriber("b@example.com").unwrap();
+
+        enqueue(&db, &[kept.clone(), removed.clone()], &items()[0])?;
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        db.set_last_digest(&removed.address, now)?;
+
+        forget_unsubscribed(&db, std::slice::from_ref(&kept))?;
+
+        assert_eq!(kept.queue(&db).depth(&db)?, 1);
+        assert_eq!(removed.queue(&db).depth(&db)?, 0);
+        assert!(db.get_last_digest(&removed.address)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn daily_schedule() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let hours_ago = |h| Some(now - chrono::Duration::hours(h));
+
+        assert!(DigestSchedule::PerRun.is_due(hours_ago(1), now));
+        assert!(DigestSchedule::Daily.is_due(None, now));
+        assert!(!DigestSchedule::Daily.is_due(hours_ago(1), now));
+        assert!(DigestSchedule::Daily.is_due(hours_ago(24), now));
+    }
+
+    #[test]
+    fn builds_digest() {
+        let digest = build_digest(&items());
+
+        assert_eq!(
+            digest.subject,
+            "ShapeYourCity digest: 1 new project, 1 update"
+        );
+        assert_eq!(
+            digest.text,
+            "New projects\n============\n\n\
+             Rezoning: 123 Main St\n6 storeys & 40 <rental> units\nhttps://shapeyourcity.ca/123-Main-St\n\n\
+             Updates\n=======\n\n\
+             DP: 456 Oak St\nstatus changed from published to archived\nhttps://shapeyourcity.ca/456-Oak-St\n"
+        );
+        assert!(digest.html.contains(
+            "<h3><a href=\"https://shapeyourcity.ca/123-Main-St\">Rezoning: 123 Main St</a></h3>\n\
+             <p>6 storeys &amp; 40 &lt;rental&gt; units</p>"
+        ));
+    }
+
+    #[test]
+    fn formats_mime_message() -> Result<()> {
+        let mut digest = build_digest(&items());
+        digest.subject = format!("Digest: {}", "café ".repeat(20));
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let message = format_message("bot@example.com", "a@example.com", &digest, now)?;
+        let formatted = String::from_utf8(message.formatted())?;
+
+        assert!(formatted.contains("From: bot@example.com\r\n"));
+        assert!(formatted.contains("To: a@example.com\r\n"));
+        assert!(formatted.contains("Date: Tue, 14 Nov 2023 22:13:20 +0000\r\n"));
+        assert!(formatted.contains("@example.com>\r\n"));
+        assert!(formatted.contains("Content-Type: multipart/alternative;"));
+        assert!(formatted.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+        assert!(formatted.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(formatted.find("text/plain") < formatted.find("text/html"));
+
+        // the long non-ASCII subject is split into encoded words that fit on a header line
+        assert!(formatted.contains("Subject: Digest: =?utf-8?b?"));
+        assert!(formatted.lines().all(|l| l.len() <= 78));
+
+        assert!(format_message("not an address", "a@example.com", &digest, now).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn validates_smtp_url() -> Result<()> {
+        let config = SmtpConfig::new("smtps://mail.example.com", Some("u"), Some("p"))?;
+        assert_eq!((config.port, config.implicit_tls), (465, true));
+
+        let config = SmtpConfig::new("smtp://localhost:1025", None, None)?;
+        assert_eq!((config.port, config.implicit_tls), (1025, false));
+
+        assert!(SmtpConfig::new("https://mail.example.com", None, None).is_err());
+        assert!(SmtpConfig::new("smtp://mail.example.com", Some("u"), None).is_err());
+        Ok(())
+    }
+
+    /// A minimal SMTP sink that accepts one message and returns everything the client sent
+    async fn smtp_sink(listener: TcpListener) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let (reader, mut writer) = socket.split();
+        let mut reader = BufReader::new(reader);
+        let mut received = String::new();
+
+        writer.write_all(b"220 sink ready\r\n").await.unwrap();
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            received.push_str(&line);
+
+            let reply: &[u8] = if in_data {
+                if line != ".\r\n" {
+                    continue;
+                }
+                in_data = false;
+                b"250 queued\r\n"
+            } else if line.starts_with("EHLO") {
+                b"250-sink\r\n250 SIZE 1000000\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                writer.write_all(b"221 bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"250 ok\r\n"
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+
+        received
+    }
+
+    #[tokio::test]
+    async fn sends_to_smtp_sink() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let sink = tokio::spawn(smtp_sink(listener));
+
+        let config = SmtpConfig::new(&format!("smtp://127.0.0.1:{}", port), None, None)?;
+        let message = Message::builder()
+            .from("bot@example.com".parse()?)
+            .to("a@example.com".parse()?)
+            .subject("hi")
+            .body(".hello\r\n".to_string())?;
+        send_email(&config, message).await?;
+
+        let received = sink.await?;
+        assert!(received.starts_with(
+            "EHLO localhost\r\n\
+             MAIL FROM:<bot@example.com>\r\n\
+             RCPT TO:<a@example.com>\r\n\
+             DATA\r\n"
+        ));
+        assert!(received.contains("Subject: hi\r\n"));
+        // a leading dot in the body is escaped so it doesn't end the message early
+        assert!(received.contains("\r\n..hello\r\n"));
+        assert!(received.ends_with("\r\n.\r\nQUIT\r\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refuses_plaintext_credentials() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let sink = tokio::spawn(smtp_sink(listener));
+
+        let config = SmtpConfig::new(&format!("smtp://127.0.0.1:{}", port), Some("u"), Some("p"))?;
+        let message = Message::builder()
+            .from("bot@example.com".parse()?)
+            .to("a@example.com".parse()?)
+            .body("hi".to_string())?;
+        let result = send_email(&config, message).await;
+
+        // the sink doesn't offer STARTTLS, so the client gives up before authenticating
+        assert!(result.is_err());
+        assert!(!sink.await?.contains("AUTH"));
+        Ok(())
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+
+use crate::{
+    db::{Database, FeedEntry, FeedEntryKind},
+    images::usable_image_url,
+    text::escape_html,
+};
+
+const SITE_URL: &str = "https://shapeyourcity.ca/";
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedFormat {
+    Atom,
+    Rss,
+    Both,
+}
+
+/// Where and what to write at the end of each run
+#[derive(Debug, Clone)]
+pub struct FeedOptions {
+    pub dir: PathBuf,
+    pub format: FeedFormat,
+    /// How many of the newest entries each feed contains
+    pub max_entries: usize,
+    /// Extra feeds containing only projects with one of these tags
+    pub tags: Vec<String>,
+    /// Public URL of `dir`, used for the feeds' self links
+    pub base_url: Option<String>,
+}
+
+/// Writes `feed.atom`/`feed.xml`, plus `tag-<tag>.atom`/`tag-<tag>.xml` for each tag feed
+pub fn write_feeds(db: &Database, options: &FeedOptions) -> Result<()> {
+    println!("{}", "Writing feeds...".bold().cyan());
+    fs::create_dir_all(&options.dir)?;
+
+    let mut feeds = vec![(
+        "feed".to_string(),
+        "ShapeYourCity projects".to_string(),
+        None,
+    )];
+    for tag in &options.tags {
+        feeds.push((
+            format!("tag-{}", slugify(tag)),
+            format!("ShapeYourCity projects: {}", tag),
+            Some(tag.as_str()),
+        ));
+    }
+
+    for (name, title, tag) in feeds {
+        let entries = db.get_feed_entries(tag, Some(options.max_entries))?;
+        let feed_entries: Vec<&FeedEntry> = entries.iter().collect();
+
+        let feed = Feed {
+            id: format!("tag:rezoning-scraper,2024:{}", name),
+            title,
+            entries: feed_entries,
+        };
+
+        if options.format != FeedFormat::Rss {
+            let file = format!("{}.atom", name);
+            let self_url = self_url(options, &file);
+            write_atomically(&options.dir.join(file), &feed.to_atom(self_url.as_deref()))?;
+        }
+        if options.format != FeedFormat::Atom {
+            let file = format!("{}.xml", name);
+            let self_url = self_url(options, &file);
+            write_atomically(&options.dir.join(file), &feed.to_rss(self_url.as_deref()))?;
+        }
+    }
+
+    println!(
+        "Wrote feeds to {}",
+        options.dir.display().to_string().green()
+    );
+    Ok(())
+}
+
+fn self_url(options: &FeedOptions, file: &str) -> Option<String> {
+    options
+        .base_url
+        .as_ref()
+        .map(|base| format!("{}/{}", base.trim_end_matches('/'), file))
+}
+
+/// Feed readers polling mid-write shouldn't see a truncated file
+fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn slugify(tag: &str) -> String {
+    let slug: String = tag
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    slug.split('-')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+struct Feed<'a> {
+    id: String,
+    title: String,
+    entries: Vec<&'a FeedEntry>,
+}
+
+impl Feed<'_> {
+    /// Feeds with no entries yet still need a valid updated date
+    fn updated(&self) -> DateTime<Utc> {
+        self.entries
+            .first()
+            .map(|e| e.created_at)
+            .unwrap_or(DateTime::UNIX_EPOCH)
+    }
+
+    fn to_atom(&self, self_url: Option<&str>) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xml.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+        xml.push_str(&format!("  <title>{}</title>\n", escape_html(&self.title)));
+        xml.push_str(&format!("  <id>{}</id>\n", escape_html(&self.id)));
+        xml.push_str(&format!(
+            "  <updated>{}</updated>\n",
+            self.updated().to_rfc3339()
+        ));
+        xml.push_str(&format!(
+            "  <link rel=\"alternate\" href=\"{}\"/>\n",
+            SITE_URL
+        ));
+        if let Some(self_url) = self_url {
+            xml.push_str(&format!(
+                "  <link rel=\"self\" href=\"{}\"/>\n",
+                escape_html(self_url)
+            ));
+        }
+        xml.push_str("  <author><name>ShapeYourCity</name></author>\n");
+        xml.push_str("  <generator>rezoning-scraper</generator>\n");
+
+        for entry in &self.entries {
+            let project = &entry.project;
+            xml.push_str("  <entry>\n");
+            xml.push_str(&format!(
+                "    <title>{}</title>\n",
+                escape_html(&entry_title(entry))
+            ));
+            xml.push_str(&format!("    <id>{}</id>\n", entry_id(entry)));
+            xml.push_str(&format!(
+                "    <link rel=\"alternate\" href=\"{}\"/>\n",
+                escape_html(&project.links.self_link)
+            ));
+            if let Some(image_url) = usable_image_url(&project.attributes.image_url) {
+                xml.push_str(&format!(
+                    "    <link rel=\"enclosure\" href=\"{}\" type=\"{}\"/>\n",
+                    escape_html(image_url),
+                    image_mime_type(image_url)
+                ));
+            }
+            xml.push_str(&format!(
+                "    <updated>{}</updated>\n",
+                entry.created_at.to_rfc3339()
+            ));
+            xml.push_str(&format!(
+                "    <summary>{}</summary>\n",
+                escape_html(&entry.summary)
+            ));
+            for tag in &project.attributes.project_tag_list {
+                xml.push_str(&format!("    <category term=\"{}\"/>\n", escape_html(tag)));
+            }
+            xml.push_str("  </entry>\n");
+        }
+
+        xml.push_str("</feed>\n");
+        xml
+    }
+
+    fn to_rss(&self, self_url: Option<&str>) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xml.push_str("<rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\">\n");
+        xml.push_str("<channel>\n");
+        xml.push_str(&format!("  <title>{}</title>\n", escape_html(&self.title)));
+        xml.push_str(&format!("  <link>{}</link>\n", SITE_URL));
+        xml.push_str("  <description>New and changed projects on ShapeYourCity</description>\n");
+        if let Some(self_url) = self_url {
+            xml.push_str(&format!(
+                "  <atom:link href=\"{}\" rel=\"self\" type=\"application/rss+xml\"/>\n",
+                escape_html(self_url)
+            ));
+        }
+        xml.push_str(&format!(
+            "  <lastBuildDate>{}</lastBuildDate>\n",
+            self.updated().to_rfc2822()
+        ));
+        xml.push_str("  <generator>rezoning-scraper</generator>\n");
+
+        for entry in &self.entries {
+            let project = &entry.project;
+            xml.push_str("  <item>\n");
+            xml.push_str(&format!(
+                "    <title>{}</title>\n",
+                escape_html(&entry_title(entry))
+            ));
+            xml.push_str(&format!(
+                "    <link>{}</link>\n",
+                escape_html(&project.links.self_link)
+            ));
+            xml.push_str(&format!(
+                "    <guid isPermaLink=\"false\">{}</guid>\n",
+                entry_id(entry)
+            ));
+            xml.push_str(&format!(
+                "    <pubDate>{}</pubDate>\n",
+                entry.created_at.to_rfc2822()
+            ));
+            xml.push_str(&format!(
+                "    <description>{}</description>\n",
+                escape_html(&entry.summary)
+            ));
+            for tag in &project.attributes.project_tag_list {
+                xml.push_str(&format!("    <category>{}</category>\n", escape_html(tag)));
+            }
+            if let Some(image_url) = usable_image_url(&project.attributes.image_url) {
+                // RSS requires a length, but we don't know it without downloading the image;
+                // 0 is the accepted way to say "unknown"
+                xml.push_str(&format!(
+                    "    <enclosure url=\"{}\" length=\"0\" type=\"{}\"/>\n",
+                    escape_html(image_url),
+                    image_mime_type(image_url)
+                ));
+            }
+            xml.push_str("  </item>\n");
+        }
+
+        xml.push_str("</channel>\n</rss>\n");
+        xml
+    }
+}
+
+fn entry_title(entry: &FeedEntry) -> String {
+    let name = entry.project.display_title();
+
+    match entry.kind {
+        FeedEntryKind::New => name,
+        FeedEntryKind::Changed => format!("Updated: {}", name),
+    }
+}
+
+fn entry_id(entry: &FeedEntry) -> String {
+    format!("tag:rezoning-scraper,2024:entry/{}", entry.id)
+}
+
+fn image_mime_type(url: &str) -> &'static str {
+    let path = url.split(['?', '#']).next().unwrap_or(url).to_lowercase();
+    if path.ends_with(".png") {
+        "image/png"
+    } else if path.ends_with(".webp") {
+        "image/webp"
+    } else if path.ends_with(".gif") {
+        "image/gif"
+    } else {
+        "image/jpeg"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Project;
+
+    fn entry(id: i64, kind: FeedEntryKind, tags: &[&str]) -> FeedEntry {
+        let mut project = Project {
+            id: format!("project-{}", id),
+            ..Default::default()
+        };
+        project.attributes.name = "123 Main St & Oak\n".to_string();
+        project.attributes.project_tag_list = tags.iter().map(|t| t.to_string()).collect();
+        project.attributes.image_url = Some("https://example.com/a.PNG?v=2".to_string());
+        project.links.self_link = "https://shapeyourcity.ca/123-main".to_string();
+
+        FeedEntry {
+            id,
+            kind,
+            project,
+            summary: "6 storeys, <40> units".to_string(),
+            created_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn renders_atom() {
+        let new = entry(2, FeedEntryKind::New, &["Rezoning"]);
+        let feed = Feed {
+            id: "tag:rezoning-scraper,2024:feed".to_string(),
+            title: "ShapeYourCity projects".to_string(),
+            entries: vec![&new],
+        };
+
+        let atom = feed.to_atom(Some("https://example.com/feeds/feed.atom"));
+        assert!(atom.contains("<updated>2023-11-14T22:13:20+00:00</updated>"));
+        assert!(atom.contains("<link rel=\"self\" href=\"https://example.com/feeds/feed.atom\"/>"));
+        assert!(atom.contains(
+            "  <entry>\n    <title>Rezoning: 123 Main St &amp; Oak</title>\n    \
+             <id>tag:rezoning-scraper,2024:entry/2</id>\n"
+        ));
+        assert!(atom.contains(
+            "<link rel=\"enclosure\" href=\"https://example.com/a.PNG?v=2\" type=\"image/png\"/>"
+        ));
+        assert!(atom.contains("<summary>6 storeys, &lt;40&gt; units</summary>"));
+        assert!(atom.contains("<category term=\"Rezoning\"/>"));
+    }
+
+    #[test]
+    fn renders_rss() {
+        let changed = entry(3, FeedEntryKind::Changed, &["Development"]);
+        let feed = Feed {
+            id: "tag:rezoning-scraper,2024:feed".to_string(),
+            title: "ShapeYourCity projects".to_string(),
+            entries: vec![&changed],
+        };
+
+        let rss = feed.to_rss(None);
+        assert!(!rss.contains("atom:link"));
+        assert!(rss.contains("<title>Updated: DP: 123 Main St &amp; Oak</title>"));
+        assert!(rss.contains("<pubDate>Tue, 14 Nov 2023 22:13:20 +0000</pubDate>"));
+        assert!(rss.contains("<category>Development</category>"));
+        assert!(rss.contains(
+            "<enclosure url=\"https://example.com/a.PNG?v=2\" length=\"0\" type=\"image/png\"/>"
+        ));
+    }
+
+    #[test]
+    fn writes_per_tag_feeds() -> Result<()> {
+        let db = Database::new_in_memory()?;
+        let rezoning = entry(1, FeedEntryKind::New, &["Rezoning"]).project;
+        let development = entry(2, FeedEntryKind::New, &["Development"]).project;
+        db.record_feed_entry(FeedEntryKind::New, &rezoning, "a rezoning")?;
+        db.record_feed_entry(FeedEntryKind::New, &development, "a development")?;
+
+        let dir = std::env::temp_dir().join(format!("rezoning-feeds-{}", std::process::id()));
+        let options = FeedOptions {
+            dir: dir.clone(),
+            format: FeedFormat::Both,
+            max_entries: 10,
+            tags: vec!["Social Housing".to_string(), "rezoning".to_string()],
+            base_url: None,
+        };
+        write_feeds(&db, &options)?;
+
+        let all = fs::read_to_string(dir.join("feed.atom"))?;
+        assert!(all.contains("a rezoning") && all.contains("a development"));
+        assert!(dir.join("feed.xml").exists());
+
+        let rezonings = fs::read_to_string(dir.join("tag-rezoning.xml"))?;
+        assert!(rezonings.contains("a rezoning") && !rezonings.contains("a development"));
+        assert!(fs::read_to_string(dir.join("tag-social-housing.atom"))?.contains("<feed"));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
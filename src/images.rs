@@ -0,0 +1,595 @@
+use std::io::Cursor;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, RgbImage,
+};
+use itertools::Itertools;
+
+use reqwest::{
+    header::{CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH},
+    StatusCode,
+};
+
+use crate::db::{CachedImage, Database};
+
+// Hard limit on image size to post to Bluesky
+pub const MAX_IMAGE_SIZE_BYTES: usize = 1_000_000;
+
+// Bluesky shows images at up to 2000px on the long edge, anything bigger is wasted bytes
+const MAX_LONG_EDGE: u32 = 2000;
+// Below this it's not worth posting the image at all
+const MIN_LONG_EDGE: u32 = 400;
+
+const MIN_JPEG_QUALITY: u8 = 40;
+const MAX_JPEG_QUALITY: u8 = 90;
+
+// Refuse to download anything bigger than this, no matter what the server claims it is
+const MAX_DOWNLOAD_BYTES: usize = 20_000_000;
+const ALLOWED_CONTENT_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/webp", "image/gif"];
+// Cached images younger than this are used without asking the server if they've changed
+const IMAGE_CACHE_FRESH_HOURS: i64 = 24;
+
+/// Sometimes they post generic images that we don't want to repost
+pub fn usable_image_url(url: &Option<String>) -> Option<&str> {
+    url.as_deref()
+        .map(str::trim)
+        .filter(|url| !url.is_empty() && !url.to_lowercase().contains("generic"))
+}
+
+/// Downloads images through a cache in the database, so retries (and every channel posting the
+/// same project) reuse one validated download
+pub struct ImageCache<'a> {
+    db: &'a Database,
+    client: &'a reqwest::Client,
+}
+
+impl<'a> ImageCache<'a> {
+    pub fn new(db: &'a Database, client: &'a reqwest::Client) -> Self {
+        ImageCache { db, client }
+    }
+
+    pub async fn get(&self, url: &str) -> Result<Vec<u8>> {
+        let cached = self.db.get_cached_image(url)?;
+
+        if let Some(cached) = &cached {
+            if cached.fetched_at > Utc::now() - chrono::Duration::hours(IMAGE_CACHE_FRESH_HOURS) {
+                eprintln!("Loaded image from cache: {}", url);
+                return Ok(cached.bytes.clone());
+            }
+        }
+
+        let mut request = self.client.get(url);
+        if let Some(etag) = cached.as_ref().and_then(|c| c.etag.as_deref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+
+        let mut response = request.send().await?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            if let Some(cached) = cached {
+                eprintln!("Image not modified, using cached copy: {}", url);
+                self.db.touch_cached_image(url)?;
+                return Ok(cached.bytes);
+            }
+        }
+
+        if !response.status().is_success() {
+            bail!(
+                "Image download failed with status {}: {}",
+                response.status(),
+                url
+            );
+        }
+
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(String::from);
+        let content_length = response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok());
+
+        check_content_type(&content_type)?;
+        if content_length.is_some_and(|len| len > MAX_DOWNLOAD_BYTES) {
+            bail!(
+                "Image is too large to download ({}b): {}",
+                content_length.unwrap_or_default(),
+                url
+            );
+        }
+
+        // Don't trust Content-Length; stop reading as soon as the cap is exceeded
+        let mut bytes = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            bytes.extend_from_slice(&chunk);
+            if bytes.len() > MAX_DOWNLOAD_BYTES {
+                bail!("Image is larger than {}b: {}", MAX_DOWNLOAD_BYTES, url);
+            }
+        }
+
+        check_decodable(&bytes).with_context(|| format!("Downloaded image is invalid: {}", url))?;
+        eprintln!("Downloaded image: {}", url);
+
+        self.db.cache_image(
+            url,
+            &CachedImage {
+                etag,
+                content_type,
+                bytes: bytes.clone(),
+                fetched_at: Utc::now(),
+            },
+        )?;
+
+        Ok(bytes)
+    }
+}
+
+fn check_content_type(content_type: &str) -> Result<()> {
+    // e.g. "image/jpeg; charset=binary"
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+
+    if ALLOWED_CONTENT_TYPES.contains(&mime.as_str()) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Unsupported image content type: {:?}",
+            content_type
+        ))
+    }
+}
+
+fn check_decodable(bytes: &[u8]) -> Result<()> {
+    let img = image::load_from_memory(bytes)?;
+    if img.width() == 0 || img.height() == 0 {
+        bail!("Image has no pixels");
+    }
+    Ok(())
+}
+
+// Hashes this many bits apart or fewer are considered the same picture
+const MAX_HASH_DISTANCE: u32 = 6;
+// An image used by this many projects is a stock image, not a picture of the site
+const MAX_PROJECTS_PER_IMAGE: usize = 3;
+
+/// Spots the stock renderings the city reuses across projects, whatever they're named
+pub struct GenericImageFilter<'a> {
+    db: &'a Database,
+    blocklist: &'a [u64],
+}
+
+impl<'a> GenericImageFilter<'a> {
+    pub fn new(db: &'a Database, blocklist: &'a [u64]) -> Self {
+        GenericImageFilter { db, blocklist }
+    }
+
+    /// Records the image's hash against the project, then checks it against the blocklist and
+    /// the images used by other projects
+    pub fn is_generic(&self, project_id: &str, url: &str, bytes: &[u8]) -> Result<bool> {
+        let hash = perceptual_hash(&image::load_from_memory(bytes)?);
+        eprintln!("Image hash: {:016x}", hash);
+
+        self.db.record_image_hash(project_id, url, hash)?;
+
+        if self
+            .blocklist
+            .iter()
+            .any(|&blocked| hamming_distance(blocked, hash) <= MAX_HASH_DISTANCE)
+        {
+            return Ok(true);
+        }
+
+        let projects_using_image = self
+            .db
+            .get_image_hashes()?
+            .into_iter()
+            .filter(|(_, other)| hamming_distance(*other, hash) <= MAX_HASH_DISTANCE)
+            .map(|(project_id, _)| project_id)
+            .unique()
+            .count();
+
+        Ok(projects_using_image >= MAX_PROJECTS_PER_IMAGE)
+    }
+}
+
+/// A 64-bit difference hash: shrink to 9x8 greyscale and record whether each pixel is brighter
+/// than its right-hand neighbour. Survives resizing and recompression, unlike a byte hash.
+pub fn perceptual_hash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y).0[0] < small.get_pixel(x + 1, y).0[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    hash
+}
+
+fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Parses a hash as printed in the logs, e.g. `f0e4c2d6b8a09182`
+pub fn parse_hash(hash: &str) -> Result<u64, String> {
+    u64::from_str_radix(hash.trim(), 16).map_err(|e| format!("invalid image hash {}: {}", hash, e))
+}
+
+/// An image that's ready to upload, plus its final dimensions
+pub struct CompressedImage {
+    pub bytes: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Shrinks an image until it's under `MAX_IMAGE_SIZE_BYTES`. Images are rotated according to
+/// their EXIF orientation and downscaled to `MAX_LONG_EDGE` first; images with transparency are
+/// kept as PNG or WebP if they fit, everything else becomes the best quality JPEG that fits.
+pub fn compress_image_until_under_size(bytes: &[u8]) -> Result<CompressedImage> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader.format();
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+
+    let already_fine = bytes.len() < MAX_IMAGE_SIZE_BYTES
+        && orientation == image::metadata::Orientation::NoTransforms
+        && img.width().max(img.height()) <= MAX_LONG_EDGE
+        && matches!(
+            format,
+            Some(ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+        );
+    if already_fine {
+        return Ok(CompressedImage {
+            bytes: bytes.to_vec(),
+            width: img.width(),
+            height: img.height(),
+        });
+    }
+
+    img.apply_orientation(orientation);
+
+    let mut long_edge = img.width().max(img.height()).min(MAX_LONG_EDGE);
+
+    loop {
+        let resized = if img.width().max(img.height()) > long_edge {
+            img.resize(long_edge, long_edge, FilterType::Lanczos3)
+        } else {
+            img.clone()
+        };
+
+        if let Some(compressed) = encode_under_size(&resized)? {
+            return Ok(compressed);
+        }
+
+        // Even the lowest acceptable quality is too big, so try again with fewer pixels
+        long_edge = long_edge * 3 / 4;
+        if long_edge < MIN_LONG_EDGE {
+            break;
+        }
+    }
+
+    bail!(
+        "Failed to compress image to under {}b",
+        MAX_IMAGE_SIZE_BYTES
+    )
+}
+
+fn encode_under_size(img: &DynamicImage) -> Result<Option<CompressedImage>> {
+    let compressed = |bytes: Vec<u8>| CompressedImage {
+        bytes,
+        width: img.width(),
+        height: img.height(),
+    };
+
+    if has_transparency(img) {
+        // Lossless formats keep the transparency, but photos rarely fit so fall back to JPEG
+        let mut png = vec![];
+        img.write_with_encoder(PngEncoder::new(&mut png))?;
+        eprintln!("Encoded image as PNG, size: {}b", png.len());
+        if png.len() < MAX_IMAGE_SIZE_BYTES {
+            return Ok(Some(compressed(png)));
+        }
+
+        let mut webp = vec![];
+        img.to_rgba8()
+            .write_with_encoder(WebPEncoder::new_lossless(&mut webp))?;
+        eprintln!("Encoded image as WebP, size: {}b", webp.len());
+        if webp.len() < MAX_IMAGE_SIZE_BYTES {
+            return Ok(Some(compressed(webp)));
+        }
+    }
+
+    let rgb = flatten_onto_white(img);
+
+    // Binary search for the highest quality that fits
+    let mut best = None;
+    let (mut low, mut high) = (MIN_JPEG_QUALITY, MAX_JPEG_QUALITY);
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let mut buffer = vec![];
+        JpegEncoder::new_with_quality(&mut buffer, quality).encode_image(&rgb)?;
+
+        eprintln!(
+            "Resized image ({}x{}, quality: {}) size: {}b",
+            rgb.width(),
+            rgb.height(),
+            quality,
+            buffer.len()
+        );
+
+        if buffer.len() < MAX_IMAGE_SIZE_BYTES {
+            best = Some(buffer);
+            low = quality + 1;
+        } else {
+            high = quality - 1;
+        }
+    }
+
+    Ok(best.map(compressed))
+}
+
+fn has_transparency(img: &DynamicImage) -> bool {
+    img.color().has_alpha() && img.to_rgba8().pixels().any(|p| p.0[3] < u8::MAX)
+}
+
+/// JPEG has no alpha channel; without this transparent areas come out black
+fn flatten_onto_white(img: &DynamicImage) -> RgbImage {
+    if !img.color().has_alpha() {
+        return img.to_rgb8();
+    }
+
+    let rgba = img.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32)) / 255) as u8;
+        image::Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Reply};
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn resize_image() {
+        let img_bytes = include_bytes!("../test_files/too_big.jpg");
+
+        eprintln!("Image size: {}b", img_bytes.len());
+
+        assert!(img_bytes.len() > MAX_IMAGE_SIZE_BYTES);
+
+        let compressed = compress_image_until_under_size(img_bytes).unwrap();
+        eprintln!("Resized image size: {}b", compressed.bytes.len());
+
+        assert!(compressed.bytes.len() < MAX_IMAGE_SIZE_BYTES);
+        assert!(compressed.width.max(compressed.height) <= MAX_LONG_EDGE);
+
+        let decoded = image::load_from_memory(&compressed.bytes).unwrap();
+        assert_eq!(decoded.width(), compressed.width);
+        assert_eq!(decoded.height(), compressed.height);
+    }
+
+    #[test]
+    fn keeps_transparency_in_large_pngs() {
+        let img = RgbaImage::from_fn(4000, 1000, |x, _| {
+            if x < 2000 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+        let mut png = vec![];
+        img.write_with_encoder(PngEncoder::new(&mut png)).unwrap();
+
+        let compressed = compress_image_until_under_size(&png).unwrap();
+
+        assert_eq!((compressed.width, compressed.height), (2000, 500));
+        assert_eq!(
+            image::guess_format(&compressed.bytes).unwrap(),
+            ImageFormat::Png
+        );
+        let decoded = image::load_from_memory(&compressed.bytes).unwrap();
+        assert!(has_transparency(&decoded));
+    }
+
+    fn gradient(width: u32, height: u32, flip: bool) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            let x = if flip { width - 1 - x } else { x };
+            let v = (x * 255 / width) as u8;
+            Rgba([v, v, (y * 255 / height) as u8, 255])
+        }))
+    }
+
+    fn encode_png(img: &DynamicImage) -> Vec<u8> {
+        let mut png = vec![];
+        img.write_with_encoder(PngEncoder::new(&mut png)).unwrap();
+        png
+    }
+
+    #[test]
+    fn perceptual_hash_survives_resizing() {
+        let big = gradient(800, 600, false);
+        let small = big.resize(200, 150, FilterType::Triangle);
+        let other = gradient(800, 600, true);
+
+        let hash = perceptual_hash(&big);
+        assert!(hamming_distance(hash, perceptual_hash(&small)) <= MAX_HASH_DISTANCE);
+        assert!(hamming_distance(hash, perceptual_hash(&other)) > MAX_HASH_DISTANCE);
+    }
+
+    #[test]
+    fn filters_blocklisted_and_reused_images() -> Result<()> {
+        let db = Database::new_in_memory()?;
+        let stock = encode_png(&gradient(400, 300, false));
+        let unique = encode_png(&gradient(400, 300, true));
+
+        let blocklist = [perceptual_hash(&image::load_from_memory(&stock)?)];
+        let filter = GenericImageFilter::new(&db, &blocklist);
+        assert!(filter.is_generic("a", "https://example.com/stock.png", &stock)?);
+        assert!(!filter.is_generic("a", "https://example.com/unique.png", &unique)?);
+
+        let filter = GenericImageFilter::new(&db, &[]);
+        assert!(!filter.is_generic("b", "https://example.com/other-name.png", &stock)?);
+        // third project to use the same picture
+        assert!(filter.is_generic("c", "https://example.com/again.png", &stock)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn skips_generic_images() {
+        assert_eq!(
+            usable_image_url(&Some(" https://example.com/a.jpg ".to_string())),
+            Some("https://example.com/a.jpg")
+        );
+        assert_eq!(
+            usable_image_url(&Some(
+                "https://example.com/Generic_Rezoning.jpg".to_string()
+            )),
+            None
+        );
+        assert_eq!(usable_image_url(&Some("".to_string())), None);
+        assert_eq!(usable_image_url(&None), None);
+    }
+
+    #[test]
+    fn validates_content_types() {
+        assert!(check_content_type("image/jpeg").is_ok());
+        assert!(check_content_type("Image/PNG; charset=binary").is_ok());
+        assert!(check_content_type("text/html; charset=utf-8").is_err());
+        assert!(check_content_type("").is_err());
+    }
+
+    #[test]
+    fn rejects_undecodable_images() {
+        assert!(check_decodable(&encode_png(&gradient(10, 10, false))).is_ok());
+        assert!(check_decodable(b"<html>not an image</html>").is_err());
+    }
+
+    #[test]
+    fn parses_hashes() {
+        assert_eq!(parse_hash("00000000000000ff"), Ok(255));
+        assert!(parse_hash("nope").is_err());
+    }
+
+    #[test]
+    fn small_images_are_left_alone() {
+        let img = RgbaImage::from_pixel(100, 50, Rgba([0, 128, 255, 255]));
+        let mut png = vec![];
+        img.write_with_encoder(PngEncoder::new(&mut png)).unwrap();
+
+        let compressed = compress_image_until_under_size(&png).unwrap();
+
+        assert_eq!(compressed.bytes, png);
+        assert_eq!((compressed.width, compressed.height), (100, 50));
+    }
+
+    #[tokio::test]
+    async fn downloads_and_caches_images() {
+        let png = encode_png(&gradient(10, 10, false));
+        let (url, server) = test_server::start(vec![Reply::new(200)
+            .header("Content-Type", "image/png")
+            .header("ETag", "\"v1\"")
+            .body(&png)])
+        .await;
+        let url = format!("{}/a.png", url);
+        let db = Database::new_in_memory().unwrap();
+        let client = reqwest::Client::new();
+        let cache = ImageCache::new(&db, &client);
+
+        assert_eq!(cache.get(&url).await.unwrap(), png);
+        // the second time it comes from the cache without a request
+        assert_eq!(cache.get(&url).await.unwrap(), png);
+        assert_eq!(server.await.unwrap().len(), 1);
+
+        let cached = db.get_cached_image(&url).unwrap().unwrap();
+        assert_eq!(cached.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(cached.content_type, "image/png");
+    }
+
+    #[tokio::test]
+    async fn revalidates_stale_images_with_their_etag() {
+        let png = encode_png(&gradient(10, 10, false));
+        let (url, server) = test_server::start(vec![Reply::new(304)]).await;
+        let url = format!("{}/a.png", url);
+        let db = Database::new_in_memory().unwrap();
+        let stale = Utc::now() - chrono::Duration::hours(IMAGE_CACHE_FRESH_HOURS + 1);
+        db.cache_image(
+            &url,
+            &CachedImage {
+                etag: Some("\"v1\"".to_string()),
+                content_type: "image/png".to_string(),
+                bytes: png.clone(),
+                fetched_at: stale,
+            },
+        )
+        .unwrap();
+        let client = reqwest::Client::new();
+
+        assert_eq!(ImageCache::new(&db, &client).get(&url).await.unwrap(), png);
+
+        let requests = server.await.unwrap();
+        assert!(requests[0].to_lowercase().contains("if-none-match: \"v1\""));
+        assert!(db.get_cached_image(&url).unwrap().unwrap().fetched_at > stale);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_downloads() {
+        let png = encode_png(&gradient(10, 10, false));
+        let (url, _server) = test_server::start(vec![
+            Reply::new(404).header("Content-Type", "image/png"),
+            Reply::new(200)
+                .header("Content-Type", "text/html")
+                .body(&png),
+            Reply::new(200)
+                .header("Content-Type", "image/png")
+                .body(&vec![0; MAX_DOWNLOAD_BYTES + 1]),
+        ])
+        .await;
+        let db = Database::new_in_memory().unwrap();
+        let client = reqwest::Client::new();
+        let cache = ImageCache::new(&db, &client);
+
+        for (path, error) in [
+            ("missing.png", "status 404"),
+            ("page.png", "content type"),
+            ("huge.png", "too large"),
+        ] {
+            let url = format!("{}/{}", url, path);
+            let result = cache.get(&url).await;
+            assert!(
+                result
+                    .as_ref()
+                    .is_err_and(|e| e.to_string().contains(error)),
+                "{}: {:?}",
+                path,
+                result.map(|bytes| bytes.len())
+            );
+            assert!(db.get_cached_image(&url).unwrap().is_none());
+        }
+    }
+}
@@ -1,6 +1,25 @@
 // This is a library only so that examples can easily use the code. The library is not intended to be used directly.
 pub mod bluesky;
+pub mod config;
 pub mod db;
+pub mod discord;
+pub mod email;
+pub mod feed;
+pub mod images;
+pub mod mastodon;
+pub mod matrix;
 pub mod models;
+pub mod notifier;
+pub mod ntfy;
 pub mod queue;
+pub mod rules;
+pub mod schedule;
+pub mod shutdown;
+pub mod site;
+pub mod slack;
 pub mod summarizer;
+pub mod telegram;
+#[cfg(test)]
+mod test_server;
+pub mod text;
+pub mod webhook;
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use reqwest::multipart::{Form, Part};
+use serde_json::{json, Value};
+use tokio::time::sleep;
+
+use crate::{
+    db::Database,
+    images::{compress_image_until_under_size, usable_image_url, GenericImageFilter, ImageCache},
+    models::{Project, SummarizedProject},
+    notifier::Notifier,
+    text::truncate,
+};
+
+// Mastodon's default limit; instances can raise it but rarely lower it
+const MAX_STATUS_CHARS: usize = 500;
+// Mastodon counts every URL as 23 characters no matter how long it is
+const URL_CHARS: usize = 23;
+// Media uploads are processed asynchronously; give up waiting after this many polls
+const MAX_MEDIA_POLLS: u32 = 10;
+
+/// An account on a Mastodon (or compatible) instance
+#[derive(Debug, Clone)]
+pub struct MastodonAuth {
+    pub instance_url: String,
+    pub access_token: String,
+}
+
+impl MastodonAuth {
+    pub fn new(instance_url: &str, access_token: &str) -> Result<Self> {
+        let instance_url = instance_url.trim().trim_end_matches('/');
+        let parsed = reqwest::Url::parse(instance_url)
+            .map_err(|e| anyhow!("Invalid Mastodon instance URL {}: {}", instance_url, e))?;
+        if parsed.scheme() != "https" && parsed.scheme() != "http" {
+            bail!("Mastodon instance URL must be http(s): {}", instance_url);
+        }
+
+        Ok(MastodonAuth {
+            instance_url: instance_url.to_string(),
+            access_token: access_token.to_string(),
+        })
+    }
+}
+
+pub async fn post_to_mastodon(
+    client: &reqwest::Client,
+    project: &Project,
+    tweet_text: &str,
+    auth: &MastodonAuth,
+    filter: &GenericImageFilter<'_>,
+    image_cache: &ImageCache<'_>,
+) -> Result<()> {
+    let mut media_ids = Vec::new();
+
+    if let Some(img_url) = usable_image_url(&project.attributes.image_url) {
+        let img_bytes = image_cache.get(img_url).await?;
+
+        if filter.is_generic(&project.id, img_url, &img_bytes)? {
+            eprintln!("Skipping generic image: {}", img_url);
+        } else {
+            let img = compress_image_until_under_size(&img_bytes)?;
+            let alt = project
+                .image_alt_text()
+                .unwrap_or("Image from ShapeYourCity API");
+
+            media_ids.push(upload_media(client, auth, img.bytes, alt).await?);
+            eprintln!("Uploaded image");
+        }
+    }
+
+    let status = status_text(project, tweet_text);
+    eprintln!("Tooting: {}", status);
+
+    client
+        .post(format!("{}/api/v1/statuses", auth.instance_url))
+        .bearer_auth(&auth.access_token)
+        // Mastodon ignores repeats of the same key for an hour, so a retry after a timeout
+        // doesn't post twice
+        .header(
+            "Idempotency-Key",
+            format!("rezoning-scraper-{}", project.id),
+        )
+        .json(&json!({
+            "status": status,
+            "media_ids": media_ids,
+            "visibility": "public"
+        }))
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Uploads an image and waits for the instance to finish processing it, returning the media ID
+async fn upload_media(
+    client: &reqwest::Client,
+    auth: &MastodonAuth,
+    bytes: Vec<u8>,
+    alt: &str,
+) -> Result<String> {
+    let mime = image::guess_format(&bytes)?.to_mime_type();
+    let extension = mime.rsplit('/').next().unwrap_or("jpg");
+    let file = Part::bytes(bytes)
+        .file_name(format!("image.{}", extension))
+        .mime_str(mime)?;
+    let form = Form::new()
+        .text("description", alt.to_string())
+        .part("file", file);
+
+    let response = client
+        .post(format!("{}/api/v2/media", auth.instance_url))
+        .bearer_auth(&auth.access_token)
+        .multipart(form)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let mut media: Value = response.json().await?;
+    let id = media["id"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Mastodon media response is missing an ID"))?
+        .to_string();
+
+    // A null URL means the instance is still processing the upload
+    let mut polls = 0;
+    while media["url"].is_null() {
+        polls += 1;
+        if polls > MAX_MEDIA_POLLS {
+            bail!("Mastodon took too long to process media {}", id);
+        }
+
+        sleep(Duration::from_secs(1)).await;
+        media = client
+            .get(format!("{}/api/v1/media/{}", auth.instance_url, id))
+            .bearer_auth(&auth.access_token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+    }
+
+    Ok(id)
+}
+
+/// Prefix, summary and link, with the summary shortened if needed to fit the character limit
+fn status_text(project: &Project, tweet_text: &str) -> String {
+    let prefix = project
+        .post_prefix()
+        .map(|p| format!("{}: ", p))
+        .unwrap_or_default();
+
+    // prefix + summary + space + link
+    let available = MAX_STATUS_CHARS - prefix.chars().count() - 1 - URL_CHARS;
+    let summary = truncate(tweet_text, available);
+
+    format!("{}{} {}", prefix, summary, project.links.self_link)
+}
+
+pub struct MastodonNotifier<'a> {
+    pub auth: &'a MastodonAuth,
+    pub generic_image_hashes: &'a [u64],
+    pub client: &'a reqwest::Client,
+}
+
+impl Notifier for MastodonNotifier<'_> {
+    type Message = SummarizedProject;
+
+    const NAME: &'static str = "Mastodon";
+    const QUEUE_NAME: &'static str = "mastodon_post_queue";
+
+    async fn send(&self, db: &Database, project: &SummarizedProject) -> Result<()> {
+        post_to_mastodon(
+            self.client,
+            &project.project,
+            &project.tweet,
+            self.auth,
+            &GenericImageFilter::new(db, self.generic_image_hashes),
+            &ImageCache::new(db, self.client),
+        )
+        .await
+    }
+
+    // avoid hitting the API too hard
+    fn rate_limit(&self) -> Option<Duration> {
+        Some(Duration::from_secs(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Reply};
+
+    fn project(tags: &[&str]) -> Project {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.project_tag_list = tags.iter().map(|t| t.to_string()).collect();
+        project.links.self_link = format!("https://shapeyourcity.ca/{}", "a".repeat(60));
+        project
+    }
+
+    #[test]
+    fn status_fits_in_limit() {
+        let project = project(&["Rezoning"]);
+
+        let status = status_text(&project, "6 storeys, 40 units");
+        assert_eq!(
+            status,
+            format!("Rezoning: 6 storeys, 40 units {}", project.links.self_link)
+        );
+
+        let long = "word ".repeat(200);
+        let status = status_text(&project, &long);
+        let text_chars = status.chars().count() - project.links.self_link.chars().count();
+        assert!(text_chars + URL_CHARS <= MAX_STATUS_CHARS);
+        assert!(text_chars + URL_CHARS > MAX_STATUS_CHARS - 5);
+        assert!(status.ends_with(&format!("… {}", project.links.self_link)));
+    }
+
+    #[tokio::test]
+    async fn uploads_media_with_alt_text() -> Result<()> {
+        let (instance_url, server) = test_server::start(vec![Reply::json(
+            200,
+            r#"{"id":"42","url":"https://files.example/42.png"}"#,
+        )])
+        .await;
+        let auth = MastodonAuth::new(&instance_url, "token")?;
+
+        let mut png = Vec::new();
+        image::RgbImage::new(1, 1)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+        // alt text that would break a hand-built body
+        let alt = "A rendering\r\n--boundary\r\nof the tower";
+        let id = upload_media(&reqwest::Client::new(), &auth, png, alt).await?;
+        assert_eq!(id, "42");
+
+        let request = server.await?.remove(0);
+        assert!(request.starts_with("POST /api/v2/media "));
+        let boundary = request
+            .lines()
+            .find_map(|l| l.strip_prefix("content-type: multipart/form-data; boundary="))
+            .unwrap();
+        assert_eq!(request.matches(boundary).count(), 4);
+        assert!(request.contains(&format!("name=\"description\"\r\n\r\n{}\r\n", alt)));
+        assert!(request.contains("filename=\"image.png\"\r\nContent-Type: image/png"));
+        Ok(())
+    }
+
+    #[test]
+    fn validates_instance_url() {
+        let auth = MastodonAuth::new("https://mastodon.social/", "token").unwrap();
+        assert_eq!(auth.instance_url, "https://mastodon.social");
+        assert!(MastodonAuth::new("mastodon.social", "token").is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use reqwest::Url;
+use serde_json::{json, Value};
+
+use crate::{
+    db::Database,
+    images::{compress_image_until_under_size, usable_image_url, GenericImageFilter, ImageCache},
+    models::SummarizedProject,
+    notifier::Notifier,
+    text::escape_html,
+};
+
+/// A room on a Matrix homeserver, posted to with a user's access token
+pub struct MatrixClient {
+    homeserver_url: Url,
+    access_token: String,
+    room_id: String,
+    upload_images: bool,
+    client: reqwest::Client,
+}
+
+impl MatrixClient {
+    pub fn new(
+        homeserver_url: &str,
+        access_token: &str,
+        room_id: &str,
+        upload_images: bool,
+    ) -> Result<Self> {
+        let homeserver_url = Url::parse(homeserver_url.trim())
+            .map_err(|e| anyhow!("Invalid Matrix homeserver URL {}: {}", homeserver_url, e))?;
+        if homeserver_url.scheme() != "https" && homeserver_url.scheme() != "http" {
+            bail!("Matrix homeserver URL must be http(s): {}", homeserver_url);
+        }
+        if !room_id.starts_with('!') || !room_id.contains(':') {
+            bail!(
+                "Matrix room ID should look like !abc123:example.org, got {}",
+                room_id
+            );
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        Ok(MatrixClient {
+            homeserver_url,
+            access_token: access_token.to_string(),
+            room_id: room_id.to_string(),
+            upload_images,
+            client,
+        })
+    }
+
+    pub async fn post_project(
+        &self,
+        project: &SummarizedProject,
+        filter: &GenericImageFilter<'_>,
+        image_cache: &ImageCache<'_>,
+    ) -> Result<()> {
+        println!("{}", "Posting to Matrix...".bold().cyan());
+        let id = &project.project.id;
+
+        // Transaction IDs make the homeserver ignore repeats, so a retry after a timeout
+        // doesn't post twice
+        self.send_event(&format!("rezoning-scraper-{}", id), &text_message(project))
+            .await?;
+
+        if self.upload_images {
+            if let Some(image) = self.upload_image(project, filter, image_cache).await? {
+                self.send_event(&format!("rezoning-scraper-{}-image", id), &image)
+                    .await?;
+            }
+        }
+
+        println!("{}", "Posted message to Matrix".green());
+        Ok(())
+    }
+
+    /// Uploads the project's main image to the media repo, returning an m.image message for it
+    async fn upload_image(
+        &self,
+        project: &SummarizedProject,
+        filter: &GenericImageFilter<'_>,
+        image_cache: &ImageCache<'_>,
+    ) -> Result<Option<Value>> {
+        let project = &project.project;
+        let Some(img_url) = usable_image_url(&project.attributes.image_url) else {
+            return Ok(None);
+        };
+
+        let img_bytes = image_cache.get(img_url).await?;
+        if filter.is_generic(&project.id, img_url, &img_bytes)? {
+            eprintln!("Skipping generic image: {}", img_url);
+            return Ok(None);
+        }
+
+        let img = compress_image_until_under_size(&img_bytes)?;
+        let mime = image::guess_format(&img.bytes)?.to_mime_type();
+        let size = img.bytes.len();
+        let filename = format!(
+            "{}.{}",
+            project.id,
+            mime.rsplit('/').next().unwrap_or("jpg")
+        );
+
+        let mut url = self.endpoint(&["_matrix", "media", "v3", "upload"]);
+        url.query_pairs_mut().append_pair("filename", &filename);
+
+        let response: Value = self
+            .client
+            .post(url)
+            .bearer_auth(&self.access_token)
+            .header("Content-Type", mime)
+            .body(img.bytes)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let content_uri = response["content_uri"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Matrix upload response is missing content_uri"))?;
+        eprintln!("Uploaded image");
+
+        Ok(Some(json!({
+            "msgtype": "m.image",
+            "body": project.image_alt_text().unwrap_or(&filename),
+            "url": content_uri,
+            "info": {
+                "mimetype": mime,
+                "size": size,
+                "w": img.width,
+                "h": img.height
+            }
+        })))
+    }
+
+    async fn send_event(&self, txn_id: &str, content: &Value) -> Result<()> {
+        let url = self.endpoint(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &self.room_id,
+            "send",
+            "m.room.message",
+            txn_id,
+        ]);
+
+        self.client
+            .put(url)
+            .bearer_auth(&self.access_token)
+            .json(content)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    /// Builds a URL under the homeserver, percent-encoding each segment (room IDs contain `!`
+    /// and `:`)
+    fn endpoint(&self, segments: &[&str]) -> Url {
+        let mut url = self.homeserver_url.clone();
+        url.path_segments_mut()
+            .expect("http(s) URLs always have a path")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+}
+
+pub struct MatrixNotifier<'a> {
+    pub client: &'a MatrixClient,
+    pub generic_image_hashes: &'a [u64],
+    pub image_client: &'a reqwest::Client,
+}
+
+impl Notifier for MatrixNotifier<'_> {
+    type Message = SummarizedProject;
+
+    const NAME: &'static str = "Matrix";
+    const QUEUE_NAME: &'static str = "matrix_post_queue";
+
+    async fn send(&self, db: &Database, project: &SummarizedProject) -> Result<()> {
+        self.client
+            .post_project(
+                project,
+                &GenericImageFilter::new(db, self.generic_image_hashes),
+                &ImageCache::new(db, self.image_client),
+            )
+            .await
+    }
+}
+
+/// An m.text message with an HTML body (title link, summary, tags) and a plain text fallback
+fn text_message(project: &SummarizedProject) -> Value {
+    let SummarizedProject { project, tweet } = project;
+    let title = project.display_title();
+    let link = &project.links.self_link;
+    let tags = project.attributes.project_tag_list.join(", ");
+
+    let mut body = format!("{}\n{}\n{}", title, tweet, link);
+    let mut formatted_body = format!(
+        "<a href=\"{}\"><strong>{}</strong></a><br>{}",
+        escape_html(link),
+        escape_html(&title),
+        escape_html(tweet)
+    );
+    if !tags.is_empty() {
+        body.push_str(&format!("\nTags: {}", tags));
+        formatted_body.push_str(&format!("<br><em>Tags: {}</em>", escape_html(&tags)));
+    }
+
+    json!({
+        "msgtype": "m.text",
+        "body": body,
+        "format": "org.matrix.custom.html",
+        "formatted_body": formatted_body
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Project;
+
+    fn summarized() -> SummarizedProject {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = "123 Main St\n".to_string();
+        project.attributes.project_tag_list = vec!["Rezoning".to_string(), "Kitsilano".to_string()];
+        project.links.self_link = "https://shapeyourcity.ca/123-main".to_string();
+
+        SummarizedProject {
+            project,
+            tweet: "6 storeys & 40 units".to_string(),
+        }
+    }
+
+    #[test]
+    fn formats_html_message() {
+        assert_eq!(
+            text_message(&summarized()),
+            json!({
+                "msgtype": "m.text",
+                "body": "Rezoning: 123 Main St\n6 storeys & 40 units\nhttps://shapeyourcity.ca/123-main\nTags: Rezoning, Kitsilano",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<a href=\"https://shapeyourcity.ca/123-main\"><strong>Rezoning: 123 Main St</strong></a><br>6 storeys &amp; 40 units<br><em>Tags: Rezoning, Kitsilano</em>"
+            })
+        );
+    }
+
+    #[test]
+    fn encodes_room_ids_in_urls() -> Result<()> {
+        let client = MatrixClient::new(
+            "https://matrix.example.org/",
+            "token",
+            "!room:example.org",
+            false,
+        )?;
+        let url = client.endpoint(&["_matrix", "client", "v3", "rooms", &client.room_id]);
+        assert_eq!(
+            url.as_str(),
+            "https://matrix.example.org/_matrix/client/v3/rooms/!room:example.org"
+        );
+
+        let url = client.endpoint(&["send", "txn/with spaces"]);
+        assert_eq!(
+            url.as_str(),
+            "https://matrix.example.org/send/txn%2Fwith%20spaces"
+        );
+
+        assert!(MatrixClient::new(
+            "https://matrix.example.org",
+            "t",
+            "#alias:example.org",
+            false
+        )
+        .is_err());
+        Ok(())
+    }
+}
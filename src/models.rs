@@ -6,6 +6,44 @@ pub struct SummarizedProject {
     pub tweet: String,
 }
 
+/// A project we've already announced whose important fields changed since the last run
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProjectUpdate {
+    pub project: Project,
+    pub changes: Vec<ProjectChange>,
+}
+
+impl ProjectUpdate {
+    /// e.g. "status changed from published to archived, description was revised"
+    pub fn describe_changes(&self) -> String {
+        let mut descriptions = Vec::new();
+
+        for change in &self.changes {
+            match change.field.as_str() {
+                "state" => descriptions.push(format!(
+                    "status changed from {} to {}",
+                    change.old_value, change.new_value
+                )),
+                "description" => descriptions.push("description was revised".to_string()),
+                _ => {}
+            }
+        }
+
+        descriptions.join(", ")
+    }
+
+    pub fn state_changed(&self) -> bool {
+        self.changes.iter().any(|c| c.field == "state")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProjectChange {
+    pub field: String,
+    pub old_value: String,
+    pub new_value: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Projects {
     pub data: Vec<Project>,
@@ -32,7 +70,7 @@ pub struct Meta {
     pub hidden: i32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Project {
     pub id: String,
     #[serde(rename = "type")]
@@ -42,6 +80,39 @@ pub struct Project {
     pub links: Links1,
 }
 
+impl Project {
+    /// Short label for the kind of application, used to prefix social media posts
+    pub fn post_prefix(&self) -> Option<&'static str> {
+        let tags = &self.attributes.project_tag_list;
+        if tags.iter().any(|tag| tag == "Development") {
+            Some("DP")
+        } else if tags.iter().any(|tag| tag == "Rezoning") {
+            Some("Rezoning")
+        } else {
+            None
+        }
+    }
+
+    /// The project's name on one line, prefixed with its kind of application if known
+    pub fn display_title(&self) -> String {
+        let name = self.attributes.name.replace('\n', "").trim().to_string();
+        match self.post_prefix() {
+            Some(prefix) => format!("{}: {}", prefix, name),
+            None => name,
+        }
+    }
+
+    /// The alt text the city wrote for the project's main image, if any
+    pub fn image_alt_text(&self) -> Option<&str> {
+        self.attributes
+            .image_description
+            .as_deref()
+            .or(self.attributes.image_caption.as_deref())
+            .map(str::trim)
+            .filter(|alt| !alt.is_empty())
+    }
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Attributes {
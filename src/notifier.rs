@@ -0,0 +1,155 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::Result;
+use chrono::Utc;
+use sentry::integrations::anyhow::capture_anyhow;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::time::sleep;
+
+use crate::{
+    db::Database,
+    queue::{Queue, MAX_MESSAGE_PROCESSING_ATTEMPTS},
+    shutdown,
+};
+
+/// An output channel fed by its own queue. Adding a channel means implementing this;
+/// `process_queue` takes care of popping, retries and the dead letter queue.
+pub trait Notifier {
+    /// What gets queued for the channel, usually a `SummarizedProject`
+    type Message: Serialize + DeserializeOwned;
+
+    /// Used in log messages and errors, e.g. "Discord"
+    const NAME: &'static str;
+    const QUEUE_NAME: &'static str;
+
+    fn send(&self, db: &Database, message: &Self::Message) -> impl Future<Output = Result<()>>;
+
+    /// How long to wait after each message, for APIs that don't like bursts
+    fn rate_limit(&self) -> Option<Duration> {
+        None
+    }
+
+    fn max_attempts(&self) -> i32 {
+        MAX_MESSAGE_PROCESSING_ATTEMPTS
+    }
+}
+
+/// The channel's queue, for pushing to it whether or not the channel is configured this run
+pub fn queue<N: Notifier>(db: &Database) -> Queue<N::Message> {
+    Queue::new(N::QUEUE_NAME, db)
+}
+
+/// Sends everything currently in the notifier's queue. Failed messages go to the back of the
+/// queue for the next run until they've used up their attempts, then to the dead letter queue.
+pub async fn process_queue<N: Notifier>(notifier: &N, db: &mut Database) -> Result<()> {
+    let queue = queue::<N>(db);
+    let depth = queue.depth(db)?;
+    let mut processed = 0;
+    if depth > 0 {
+        println!("Processing {} messages in {} queue", depth, N::NAME);
+    }
+
+    // process everything currently in the queue, unless we're shutting down
+    while processed < depth && !shutdown::requested() {
+        if let Some(mut message) = queue.pop(db)? {
+            if let Err(e) = notifier.send(db, &message.payload).await {
+                message.attempts += 1;
+                message.last_attempt = Some(Utc::now());
+                eprintln!("Error sending to {}: {}", N::NAME, e);
+                if message.attempts < notifier.max_attempts() {
+                    queue.push_message(db, &message)?;
+                } else {
+                    eprintln!(
+                        "Message failed {} times; moving to dead letter queue",
+                        message.attempts
+                    );
+                    queue.push_to_dead_letter(db, &message, &e.to_string())?;
+                    capture_anyhow(&e.context(format!(
+                        "Failed to send to {}, moving to dead letter queue",
+                        N::NAME
+                    )));
+                }
+            }
+
+            if let Some(delay) = notifier.rate_limit() {
+                sleep(delay).await;
+            }
+        }
+        processed += 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use std::cell::RefCell;
+
+    /// Records what it sends and fails for any message starting with "bad"
+    #[derive(Default)]
+    struct FakeNotifier {
+        sent: RefCell<Vec<String>>,
+    }
+
+    impl Notifier for FakeNotifier {
+        type Message = String;
+
+        const NAME: &'static str = "Fake";
+        const QUEUE_NAME: &'static str = "fake_queue";
+
+        async fn send(&self, _db: &Database, message: &String) -> Result<()> {
+            if message.starts_with("bad") {
+                bail!("can't send {}", message);
+            }
+            self.sent.borrow_mut().push(message.clone());
+            Ok(())
+        }
+
+        fn max_attempts(&self) -> i32 {
+            2
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_queued_messages_in_order() -> Result<()> {
+        let mut db = Database::new_in_memory()?;
+        let notifier = FakeNotifier::default();
+        let queue = queue::<FakeNotifier>(&db);
+        queue.push(&db, "one".to_string())?;
+        queue.push(&db, "two".to_string())?;
+
+        process_queue(&notifier, &mut db).await?;
+
+        assert_eq!(*notifier.sent.borrow(), vec!["one", "two"]);
+        assert_eq!(queue.depth(&db)?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retries_then_dead_letters_failures() -> Result<()> {
+        let mut db = Database::new_in_memory()?;
+        let notifier = FakeNotifier::default();
+        let queue = queue::<FakeNotifier>(&db);
+        queue.push(&db, "bad".to_string())?;
+        queue.push(&db, "good".to_string())?;
+
+        // First run: the failure goes back on the queue behind the message that worked
+        process_queue(&notifier, &mut db).await?;
+        assert_eq!(*notifier.sent.borrow(), vec!["good"]);
+        let retry = queue.peek_all(&db)?;
+        assert_eq!(retry.len(), 1);
+        assert_eq!(retry[0].attempts, 1);
+        assert!(retry[0].last_attempt.is_some());
+
+        // Second run uses up max_attempts
+        process_queue(&notifier, &mut db).await?;
+        assert_eq!(queue.depth(&db)?, 0);
+        let (dead, error) = queue.pop_from_dead_letter(&mut db)?.unwrap();
+        assert_eq!(dead.payload, "bad");
+        assert_eq!(dead.attempts, 2);
+        assert_eq!(error, "can't send bad");
+        Ok(())
+    }
+}
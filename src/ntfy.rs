@@ -0,0 +1,327 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    db::Database,
+    images::usable_image_url,
+    models::{Project, SummarizedProject},
+    notifier::Notifier,
+};
+
+pub const DEFAULT_SERVER_URL: &str = "https://ntfy.sh";
+
+/// An ntfy topic and the projects it wants. A project matches if it satisfies every kind of rule
+/// given (any one of the tags, any one of the keywords, any one of the address ranges); a topic
+/// with no rules gets everything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NtfyTopic {
+    pub name: String,
+    pub tags: Vec<String>,
+    pub keywords: Vec<String>,
+    pub addresses: Vec<AddressRange>,
+}
+
+/// Street numbers `from..=to` on one street, e.g. `2000-2999 W 4th Ave`
+#[derive(Debug, Clone)]
+pub struct AddressRange {
+    pub from: u32,
+    pub to: u32,
+    pub street: String,
+    /// Addresses on `street`, compiled once rather than for every project
+    pattern: Regex,
+}
+
+impl PartialEq for AddressRange {
+    fn eq(&self, other: &Self) -> bool {
+        (self.from, self.to, &self.street) == (other.from, other.to, &other.street)
+    }
+}
+
+/// Parses an `--ntfy-topic` value like `kits:tag=Rezoning;keyword=Kitsilano;address=2000-2999 W 4th Ave`
+pub fn parse_topic(value: &str) -> Result<NtfyTopic, String> {
+    let (name, rules) = value.split_once(':').unwrap_or((value, ""));
+    let name = name.trim();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("invalid ntfy topic name {:?}", name));
+    }
+
+    let mut topic = NtfyTopic {
+        name: name.to_string(),
+        tags: vec![],
+        keywords: vec![],
+        addresses: vec![],
+    };
+
+    for rule in rules.split(';').map(str::trim).filter(|r| !r.is_empty()) {
+        match rule.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+            Some(("tag", tag)) if !tag.is_empty() => topic.tags.push(tag.to_string()),
+            Some(("keyword", keyword)) if !keyword.is_empty() => {
+                topic.keywords.push(keyword.to_string())
+            }
+            Some(("address", address)) => topic.addresses.push(parse_address_range(address)?),
+            _ => {
+                return Err(format!(
+                    "expected tag=..., keyword=... or address=..., got {:?}",
+                    rule
+                ))
+            }
+        }
+    }
+
+    Ok(topic)
+}
+
+fn parse_address_range(value: &str) -> Result<AddressRange, String> {
+    let error = || {
+        format!(
+            "expected an address range like 2000-2999 W 4th Ave, got {:?}",
+            value
+        )
+    };
+
+    let (numbers, street) = value.split_once(' ').ok_or_else(error)?;
+    let (from, to) = numbers.split_once('-').unwrap_or((numbers, numbers));
+    let from: u32 = from.parse().map_err(|_| error())?;
+    let to: u32 = to.parse().map_err(|_| error())?;
+    if from > to || street.trim().is_empty() {
+        return Err(error());
+    }
+
+    Ok(AddressRange::new(from, to, street.trim()))
+}
+
+impl NtfyTopic {
+    pub fn matches(&self, project: &Project) -> bool {
+        let attributes = &project.attributes;
+        let text = format!(
+            "{}\n{}",
+            attributes.name,
+            attributes.description.as_deref().unwrap_or_default()
+        )
+        .to_lowercase();
+
+        let tag_matches = self.tags.is_empty()
+            || self.tags.iter().any(|tag| {
+                attributes
+                    .project_tag_list
+                    .iter()
+                    .any(|t| t.eq_ignore_ascii_case(tag))
+            });
+        let keyword_matches = self.keywords.is_empty()
+            || self
+                .keywords
+                .iter()
+                .any(|keyword| text.contains(&keyword.to_lowercase()));
+        let address_matches =
+            self.addresses.is_empty() || self.addresses.iter().any(|range| range.matches(&text));
+
+        tag_matches && keyword_matches && address_matches
+    }
+}
+
+impl AddressRange {
+    pub fn new(from: u32, to: u32, street: &str) -> Self {
+        // "W." and "W" should both match, so dots are dropped from the street and the text
+        let escaped = regex::escape(&street.to_lowercase().replace('.', ""));
+        let pattern = Regex::new(&format!(r"\b(\d+)(?:\s*-\s*(\d+))?\s+{}\b", escaped)).unwrap();
+
+        AddressRange {
+            from,
+            to,
+            street: street.to_string(),
+            pattern,
+        }
+    }
+
+    /// Looks for addresses like "2050 W 4th Ave" or "2050-2070 W. 4th Ave" on this street where
+    /// any of the numbers fall in the range
+    fn matches(&self, text: &str) -> bool {
+        let text = text.replace('.', "").to_lowercase();
+
+        let matched = self.pattern.captures_iter(&text).any(|captures| {
+            let first: u32 = captures[1].parse().unwrap_or(0);
+            let last: u32 = captures
+                .get(2)
+                .and_then(|m| m.as_str().parse().ok())
+                .unwrap_or(first);
+            first <= self.to && last >= self.from
+        });
+        matched
+    }
+}
+
+/// One project to publish to one topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NtfyMessage {
+    pub topic: String,
+    pub project: SummarizedProject,
+}
+
+pub struct NtfyClient {
+    server_url: String,
+    access_token: Option<String>,
+    client: reqwest::Client,
+}
+
+impl NtfyClient {
+    pub fn new(server_url: Option<&str>, access_token: Option<&str>) -> Result<Self> {
+        let server_url = server_url
+            .unwrap_or(DEFAULT_SERVER_URL)
+            .trim()
+            .trim_end_matches('/');
+        let parsed = reqwest::Url::parse(server_url)
+            .map_err(|e| anyhow!("Invalid ntfy server URL {}: {}", server_url, e))?;
+        if parsed.scheme() != "https" && parsed.scheme() != "http" {
+            bail!("ntfy server URL must be http(s): {}", server_url);
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(20))
+            .build()?;
+
+        Ok(NtfyClient {
+            server_url: server_url.to_string(),
+            access_token: access_token.map(String::from),
+            client,
+        })
+    }
+
+    pub async fn publish(&self, message: &NtfyMessage) -> Result<()> {
+        println!(
+            "{}",
+            format!("Publishing to ntfy topic {}...", message.topic)
+                .bold()
+                .cyan()
+        );
+
+        // ntfy accepts JSON when it's POSTed to the server root rather than the topic URL
+        let mut request = self
+            .client
+            .post(&self.server_url)
+            .json(&notification(message));
+        if let Some(token) = &self.access_token {
+            request = request.bearer_auth(token);
+        }
+        request.send().await?.error_for_status()?;
+
+        println!("{}", "Published to ntfy".green());
+        Ok(())
+    }
+}
+
+impl Notifier for NtfyClient {
+    type Message = NtfyMessage;
+
+    const NAME: &'static str = "ntfy";
+    const QUEUE_NAME: &'static str = "ntfy_queue";
+
+    async fn send(&self, _db: &Database, message: &NtfyMessage) -> Result<()> {
+        self.publish(message).await
+    }
+}
+
+fn notification(message: &NtfyMessage) -> serde_json::Value {
+    let SummarizedProject { project, tweet } = &message.project;
+    let title = project.display_title();
+
+    let mut body = json!({
+        "topic": message.topic,
+        "title": title,
+        "message": tweet,
+        "tags": project.attributes.project_tag_list,
+        "click": project.links.self_link
+    });
+    if let Some(image_url) = usable_image_url(&project.attributes.image_url) {
+        body["attach"] = json!(image_url);
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(name: &str, tags: &[&str]) -> Project {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = name.to_string();
+        project.attributes.project_tag_list = tags.iter().map(|t| t.to_string()).collect();
+        project.links.self_link = "https://shapeyourcity.ca/foo".to_string();
+        project
+    }
+
+    #[test]
+    fn parses_topics() {
+        assert_eq!(
+            parse_topic("kits:tag=Rezoning; keyword=Kitsilano;address=2000-2999 W 4th Ave"),
+            Ok(NtfyTopic {
+                name: "kits".to_string(),
+                tags: vec!["Rezoning".to_string()],
+                keywords: vec!["Kitsilano".to_string()],
+                addresses: vec![AddressRange::new(2000, 2999, "W 4th Ave")],
+            })
+        );
+        assert_eq!(parse_topic("everything").unwrap().tags.len(), 0);
+        assert!(parse_topic("bad topic").is_err());
+        assert!(parse_topic("kits:colour=red").is_err());
+        assert!(parse_topic("kits:address=W 4th Ave").is_err());
+        assert!(parse_topic("kits:address=3000-2000 W 4th Ave").is_err());
+    }
+
+    #[test]
+    fn matches_rules() {
+        let topic = parse_topic("kits:tag=Rezoning;address=2000-2999 W 4th Ave").unwrap();
+
+        assert!(topic.matches(&project(
+            "2050 W. 4th Ave rezoning application",
+            &["Rezoning"]
+        )));
+        // a multi-lot address overlapping the range
+        assert!(topic.matches(&project("1990-2010 W 4th Ave", &["Rezoning"])));
+        assert!(!topic.matches(&project("3050 W 4th Ave", &["Rezoning"])));
+        assert!(!topic.matches(&project("2050 W 4th Ave", &["Development"])));
+        assert!(!topic.matches(&project("2050 W 41st Ave", &["Rezoning"])));
+
+        let keywords = parse_topic("mp:keyword=mount pleasant;keyword=main st").unwrap();
+        let mut with_description = project("123 E 7th Ave", &[]);
+        with_description.attributes.description = Some("<p>In Mount Pleasant</p>".to_string());
+        assert!(keywords.matches(&with_description));
+        assert!(!keywords.matches(&project("123 E 7th Ave", &[])));
+    }
+
+    #[test]
+    fn builds_notification() {
+        let mut project = project("2050 W 4th Ave", &["Rezoning"]);
+        project.attributes.image_url = Some("https://example.com/a.jpg".to_string());
+        let message = NtfyMessage {
+            topic: "kits".to_string(),
+            project: SummarizedProject {
+                project,
+                tweet: "6 storeys".to_string(),
+            },
+        };
+
+        assert_eq!(
+            notification(&message),
+            json!({
+                "topic": "kits",
+                "title": "Rezoning: 2050 W 4th Ave",
+                "message": "6 storeys",
+                "tags": ["Rezoning"],
+                "click": "https://shapeyourcity.ca/foo",
+                "attach": "https://example.com/a.jpg"
+            })
+        );
+    }
+}
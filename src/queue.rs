@@ -4,6 +4,9 @@ use rusqlite::{params, Connection, OptionalExtension};
 use serde::{de::DeserializeOwned, Serialize};
 use std::marker::PhantomData;
 
+/// How many times a message is tried before it goes to the dead letter queue
+pub const MAX_MESSAGE_PROCESSING_ATTEMPTS: i32 = 3;
+
 #[derive(Debug, Clone)]
 pub struct Queue<T> {
     name: String,
@@ -117,6 +120,41 @@ where
         }
     }
 
+    /// Reads every message in the queue without removing them, oldest first. Used when a batch
+    /// of messages must only be removed once all of them have been handled.
+    pub fn peek_all(&self, conn: &Connection) -> Result<Vec<QueueMessage<T>>> {
+        let mut stmt = conn.prepare(
+            "SELECT id, payload, attempts, created_at, last_attempt 
+             FROM Queue 
+             WHERE queue_name = ?1 
+             ORDER BY id ASC",
+        )?;
+        let rows = stmt.query_map(params![self.name], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, i32>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<i64>>(4)?,
+            ))
+        })?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            let (id, payload_str, attempts, created_at, last_attempt) = row?;
+            messages.push(QueueMessage {
+                id,
+                payload: serde_json::from_str(&payload_str)?,
+                attempts,
+                created_at: DateTime::from_timestamp(created_at, 0).unwrap_or_default(),
+                last_attempt: last_attempt
+                    .map(|ts| DateTime::from_timestamp(ts, 0).unwrap_or_default()),
+            });
+        }
+
+        Ok(messages)
+    }
+
     pub fn push_to_dead_letter(
         &self,
         conn: &Connection,
@@ -197,7 +235,6 @@ where
         }
     }
 
-    #[allow(dead_code)]
     pub fn remove(&self, conn: &Connection, msg_id: i64) -> Result<()> {
         conn.execute(
             "DELETE FROM Queue WHERE id = ? AND queue_name = ?",
@@ -205,6 +242,30 @@ where
         )?;
         Ok(())
     }
+
+    /// Deletes every message in the queue, including dead letters
+    pub fn clear(&self, conn: &Connection) -> Result<()> {
+        conn.execute("DELETE FROM Queue WHERE queue_name = ?", params![self.name])?;
+        conn.execute(
+            "DELETE FROM DeadLetterQueue WHERE queue_name = ?",
+            params![self.name],
+        )?;
+        Ok(())
+    }
+}
+
+/// Names of the queues starting with `prefix` that hold messages or dead letters
+pub fn names_with_prefix(conn: &Connection, prefix: &str) -> Result<Vec<String>> {
+    initialize(conn)?;
+    let mut stmt = conn.prepare(
+        "SELECT queue_name FROM Queue WHERE substr(queue_name, 1, length(?1)) = ?1
+         UNION
+         SELECT queue_name FROM DeadLetterQueue WHERE substr(queue_name, 1, length(?1)) = ?1",
+    )?;
+    let names = stmt
+        .query_map(params![prefix], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    Ok(names)
 }
 
 fn initialize(conn: &Connection) -> Result<()> {
@@ -329,6 +390,61 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_peek_all_leaves_messages() -> Result<()> {
+        let conn = Connection::open_in_memory()?;
+        let queue: Queue<TestMessage> = Queue::new("test_queue", &conn);
+
+        for i in 1..=2 {
+            queue.push(
+                &conn,
+                TestMessage {
+                    content: format!("msg{}", i),
+                },
+            )?;
+        }
+
+        let messages = queue.peek_all(&conn)?;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].payload.content, "msg1");
+        assert_eq!(queue.depth(&conn)?, 2);
+
+        queue.remove(&conn, messages[0].id)?;
+        assert_eq!(queue.depth(&conn)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clear_and_names_with_prefix() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        let kept: Queue<TestMessage> = Queue::new("digest:a", &conn);
+        let cleared: Queue<TestMessage> = Queue::new("digest:b", &conn);
+        let other: Queue<TestMessage> = Queue::new("other", &conn);
+
+        for queue in [&kept, &cleared, &other] {
+            queue.push(
+                &conn,
+                TestMessage {
+                    content: "msg".to_string(),
+                },
+            )?;
+        }
+        let message = cleared.pop(&mut conn)?.unwrap();
+        cleared.push_to_dead_letter(&conn, &message, "failed")?;
+
+        let mut names = names_with_prefix(&conn, "digest:")?;
+        names.sort();
+        assert_eq!(names, vec!["digest:a", "digest:b"]);
+
+        cleared.clear(&conn)?;
+        assert_eq!(names_with_prefix(&conn, "digest:")?, vec!["digest:a"]);
+        assert!(cleared.pop_from_dead_letter(&mut conn)?.is_none());
+        assert_eq!(kept.depth(&conn)?, 1);
+
+        Ok(())
+    }
+
     #[test]
     fn test_message_ordering() -> Result<()> {
         let conn = Connection::open_in_memory()?;
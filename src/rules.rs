@@ -0,0 +1,329 @@
+use regex::Regex;
+
+use crate::models::Project;
+
+/// Every channel rules can be written for
+pub const CHANNELS: &[&str] = &[
+    "slack", "discord", "matrix", "telegram", "ntfy", "webhook", "bluesky", "mastodon", "email",
+];
+
+const KINDS: &[&str] = &[
+    "tag",
+    "state",
+    "name",
+    "description",
+    "neighbourhood",
+    "site",
+];
+
+// Vancouver's 22 local areas, with the shorter names people also use for them
+const NEIGHBOURHOODS: &[(&str, &[&str])] = &[
+    ("Arbutus Ridge", &[]),
+    ("Downtown", &["yaletown", "coal harbour", "gastown"]),
+    ("Dunbar-Southlands", &["dunbar", "southlands"]),
+    ("Fairview", &[]),
+    ("Grandview-Woodland", &["grandview"]),
+    ("Hastings-Sunrise", &["hastings sunrise"]),
+    ("Kensington-Cedar Cottage", &["kensington", "cedar cottage"]),
+    ("Kerrisdale", &[]),
+    ("Killarney", &[]),
+    ("Kitsilano", &["kits"]),
+    ("Marpole", &[]),
+    ("Mount Pleasant", &[]),
+    ("Oakridge", &[]),
+    ("Renfrew-Collingwood", &["renfrew", "collingwood"]),
+    ("Riley Park", &[]),
+    ("Shaughnessy", &[]),
+    ("South Cambie", &[]),
+    ("Strathcona", &[]),
+    ("Sunset", &[]),
+    ("Victoria-Fraserview", &["fraserview"]),
+    ("West End", &[]),
+    ("West Point Grey", &["point grey"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Include,
+    Exclude,
+}
+
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Tag(String),
+    State(String),
+    Name(Regex),
+    Description(Regex),
+    Neighbourhood(String),
+    Site(String),
+}
+
+/// One `--rule`, e.g. `bluesky:include:tag=Rezoning`
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub channel: String,
+    pub action: Action,
+    pub condition: Condition,
+    /// The rule as written, for explaining decisions
+    pub source: String,
+}
+
+/// Whether a channel gets a project, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verdict {
+    pub allowed: bool,
+    pub reason: String,
+}
+
+/// Parses a `--rule` value: `CHANNEL:include|exclude:KIND=VALUE`
+pub fn parse_rule(value: &str) -> Result<Rule, String> {
+    let mut parts = value.splitn(3, ':').map(str::trim);
+    let (Some(channel), Some(action), Some(condition)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(format!(
+            "expected CHANNEL:include|exclude:KIND=VALUE, got {:?}",
+            value
+        ));
+    };
+
+    let channel = channel.to_lowercase();
+    if !CHANNELS.contains(&channel.as_str()) {
+        return Err(format!(
+            "unknown channel {:?}; expected one of {}",
+            channel,
+            CHANNELS.join(", ")
+        ));
+    }
+
+    let action = match action {
+        "include" => Action::Include,
+        "exclude" => Action::Exclude,
+        _ => return Err(format!("expected include or exclude, got {:?}", action)),
+    };
+
+    let (kind, pattern) = condition
+        .split_once('=')
+        .map(|(k, v)| (k.trim(), v.trim()))
+        .filter(|(_, v)| !v.is_empty())
+        .ok_or_else(|| format!("expected KIND=VALUE, got {:?}", condition))?;
+    let regex = || Regex::new(pattern).map_err(|e| format!("invalid regex {:?}: {}", pattern, e));
+    let condition = match kind {
+        "tag" => Condition::Tag(pattern.to_string()),
+        "state" => Condition::State(pattern.to_string()),
+        "name" => Condition::Name(regex()?),
+        "description" => Condition::Description(regex()?),
+        "neighbourhood" => Condition::Neighbourhood(pattern.to_string()),
+        "site" => Condition::Site(pattern.to_string()),
+        _ => {
+            return Err(format!(
+                "unknown rule kind {:?}; expected one of {}",
+                kind,
+                KINDS.join(", ")
+            ))
+        }
+    };
+
+    Ok(Rule {
+        channel,
+        action,
+        condition,
+        source: value.trim().to_string(),
+    })
+}
+
+impl Condition {
+    fn matches(&self, project: &Project) -> bool {
+        let attributes = &project.attributes;
+        match self {
+            Condition::Tag(tag) => attributes
+                .project_tag_list
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(tag)),
+            Condition::State(state) => attributes.state.eq_ignore_ascii_case(state),
+            Condition::Name(re) => re.is_match(&attributes.name),
+            Condition::Description(re) => {
+                re.is_match(attributes.description.as_deref().unwrap_or_default())
+            }
+            Condition::Neighbourhood(name) => {
+                neighbourhood(project).is_some_and(|n| n.eq_ignore_ascii_case(name))
+            }
+            Condition::Site(site) => project.relationships.site.data.id == *site,
+        }
+    }
+}
+
+/// Decides whether `channel` gets `project`. A channel with no rules gets everything; otherwise
+/// the project must match one of its include rules (if it has any) and none of its exclude rules.
+pub fn evaluate(rules: &[Rule], channel: &str, project: &Project) -> Verdict {
+    let rules: Vec<&Rule> = rules.iter().filter(|r| r.channel == channel).collect();
+    if rules.is_empty() {
+        return Verdict {
+            allowed: true,
+            reason: "no rules".to_string(),
+        };
+    }
+
+    if let Some(rule) = rules
+        .iter()
+        .find(|r| r.action == Action::Exclude && r.condition.matches(project))
+    {
+        return Verdict {
+            allowed: false,
+            reason: format!("excluded by {}", rule.source),
+        };
+    }
+
+    let mut includes = rules
+        .iter()
+        .filter(|r| r.action == Action::Include)
+        .peekable();
+    if includes.peek().is_none() {
+        return Verdict {
+            allowed: true,
+            reason: "no exclude rule matched".to_string(),
+        };
+    }
+    match includes.find(|r| r.condition.matches(project)) {
+        Some(rule) => Verdict {
+            allowed: true,
+            reason: format!("included by {}", rule.source),
+        },
+        None => Verdict {
+            allowed: false,
+            reason: "no include rule matched".to_string(),
+        },
+    }
+}
+
+pub fn allows(rules: &[Rule], channel: &str, project: &Project) -> bool {
+    evaluate(rules, channel, project).allowed
+}
+
+/// The Vancouver neighbourhood a project is in, going by the first one mentioned in its name or
+/// else its description
+pub fn neighbourhood(project: &Project) -> Option<&'static str> {
+    let attributes = &project.attributes;
+    first_neighbourhood(&attributes.name)
+        .or_else(|| first_neighbourhood(attributes.description.as_deref().unwrap_or_default()))
+}
+
+fn first_neighbourhood(text: &str) -> Option<&'static str> {
+    let text = text.to_lowercase();
+    NEIGHBOURHOODS
+        .iter()
+        .filter_map(|(name, aliases)| {
+            std::iter::once(name.to_lowercase())
+                .chain(aliases.iter().map(|a| a.to_string()))
+                .filter_map(|candidate| find_word(&text, &candidate))
+                .min()
+                .map(|position| (position, *name))
+        })
+        .min()
+        .map(|(_, name)| name)
+}
+
+/// Position of `word` in `text` where it isn't part of a longer word
+fn find_word(text: &str, word: &str) -> Option<usize> {
+    text.match_indices(word).map(|(i, _)| i).find(|&i| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(name: &str, state: &str, tags: &[&str]) -> Project {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = name.to_string();
+        project.attributes.state = state.to_string();
+        project.attributes.project_tag_list = tags.iter().map(|t| t.to_string()).collect();
+        project.relationships.site.data.id = "1".to_string();
+        project
+    }
+
+    fn rules(values: &[&str]) -> Vec<Rule> {
+        values.iter().map(|v| parse_rule(v).unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule = parse_rule("Bluesky:include:tag=Rezoning").unwrap();
+        assert_eq!(rule.channel, "bluesky");
+        assert_eq!(rule.action, Action::Include);
+        assert!(matches!(rule.condition, Condition::Tag(ref t) if t == "Rezoning"));
+
+        // the regex can contain colons and equals signs
+        assert!(matches!(
+            parse_rule("slack:exclude:name=(?i)^test: a=b")
+                .unwrap()
+                .condition,
+            Condition::Name(_)
+        ));
+
+        assert!(parse_rule("myspace:include:tag=Rezoning").is_err());
+        assert!(parse_rule("slack:maybe:tag=Rezoning").is_err());
+        assert!(parse_rule("slack:include:colour=red").is_err());
+        assert!(parse_rule("slack:include:name=(").is_err());
+        assert!(parse_rule("slack:include").is_err());
+    }
+
+    #[test]
+    fn evaluates_rules_per_channel() {
+        let rules = rules(&[
+            "bluesky:include:tag=Rezoning",
+            "bluesky:include:neighbourhood=Kitsilano",
+            "slack:exclude:state=archived",
+            "discord:include:site=2",
+        ]);
+        let rezoning = project("123 Main St", "published", &["Rezoning"]);
+        let kits = project("2050 W 4th Ave (Kitsilano)", "archived", &["Development"]);
+
+        assert_eq!(
+            evaluate(&rules, "bluesky", &rezoning).reason,
+            "included by bluesky:include:tag=Rezoning"
+        );
+        assert!(allows(&rules, "bluesky", &kits));
+        assert!(!allows(
+            &rules,
+            "bluesky",
+            &project("123 Main St", "published", &["Development"])
+        ));
+
+        assert!(allows(&rules, "slack", &rezoning));
+        assert_eq!(
+            evaluate(&rules, "slack", &kits),
+            Verdict {
+                allowed: false,
+                reason: "excluded by slack:exclude:state=archived".to_string(),
+            }
+        );
+
+        assert!(!allows(&rules, "discord", &rezoning));
+        assert_eq!(evaluate(&rules, "matrix", &kits).reason, "no rules");
+    }
+
+    #[test]
+    fn extracts_neighbourhoods() {
+        assert_eq!(
+            neighbourhood(&project("Rezoning in Mount Pleasant", "", &[])),
+            Some("Mount Pleasant")
+        );
+        // the earliest mention wins, and aliases count
+        assert_eq!(
+            neighbourhood(&project("Kits townhouses near Dunbar", "", &[])),
+            Some("Kitsilano")
+        );
+        // not part of a longer word
+        assert_eq!(neighbourhood(&project("Kitsch Gallery", "", &[])), None);
+
+        let mut described = project("123 Main St", "", &[]);
+        described.attributes.description = Some("<p>Located in Strathcona.</p>".to_string());
+        assert_eq!(neighbourhood(&described), Some("Strathcona"));
+    }
+}
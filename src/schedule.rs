@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use chrono::NaiveTime;
+
+/// A daily window when the daemon doesn't post, e.g. `22:00-07:00`. Scraping and summarizing
+/// carry on and the posts wait in their queues until it's over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // wraps past midnight
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// Parses `--quiet-hours`: `HH:MM-HH:MM` in local time
+pub fn parse_quiet_hours(value: &str) -> Result<QuietHours, String> {
+    let (start, end) = value
+        .split_once('-')
+        .ok_or_else(|| format!("expected HH:MM-HH:MM, got {:?}", value))?;
+    let time = |t: &str| {
+        NaiveTime::parse_from_str(t.trim(), "%H:%M")
+            .map_err(|_| format!("expected a time like 22:00, got {:?}", t.trim()))
+    };
+    let quiet_hours = QuietHours {
+        start: time(start)?,
+        end: time(end)?,
+    };
+    if quiet_hours.start == quiet_hours.end {
+        return Err("quiet hours can't start and end at the same time".to_string());
+    }
+    Ok(quiet_hours)
+}
+
+/// How long to wait before the next daemon run: the interval plus or minus up to `jitter`, so
+/// runs don't always hit the API at the same second
+pub fn next_delay(interval: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return interval;
+    }
+    let jitter = jitter.min(interval);
+    let offset = Duration::from_millis(fastrand::u64(0..=2 * jitter.as_millis() as u64));
+    interval - jitter + offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let overnight = parse_quiet_hours("22:00-07:00").unwrap();
+        assert!(overnight.contains(at("23:30")));
+        assert!(overnight.contains(at("03:00")));
+        assert!(!overnight.contains(at("07:00")));
+        assert!(!overnight.contains(at("12:00")));
+
+        let lunch = parse_quiet_hours("12:00 - 13:30").unwrap();
+        assert!(lunch.contains(at("12:45")));
+        assert!(!lunch.contains(at("21:00")));
+
+        assert!(parse_quiet_hours("22:00").is_err());
+        assert!(parse_quiet_hours("10pm-7am").is_err());
+        assert!(parse_quiet_hours("07:00-07:00").is_err());
+    }
+
+    #[test]
+    fn jitters_around_the_interval() {
+        let interval = Duration::from_secs(15 * 60);
+        assert_eq!(next_delay(interval, Duration::ZERO), interval);
+        for _ in 0..100 {
+            let delay = next_delay(interval, Duration::from_secs(60));
+            assert!(delay >= Duration::from_secs(14 * 60));
+            assert!(delay <= Duration::from_secs(16 * 60));
+        }
+        // jitter can't make the delay negative
+        assert!(
+            next_delay(Duration::from_secs(10), Duration::from_secs(60)) <= Duration::from_secs(20)
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use tokio::{signal::ctrl_c, sync::Notify, time::sleep};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+static NOTIFY: Notify = Notify::const_new();
+
+/// Handles Ctrl-C (and SIGTERM on Unix) by asking everything to stop after the message it's
+/// working on, so nothing is half-posted. A second signal exits straight away.
+pub fn listen() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            #[cfg(unix)]
+            let signalled = tokio::select! {
+                _ = terminate.recv() => true,
+                result = ctrl_c() => result.is_ok(),
+            };
+            #[cfg(not(unix))]
+            let signalled = ctrl_c().await.is_ok();
+            if !signalled {
+                eprintln!("Couldn't listen for shutdown signals");
+                return;
+            }
+
+            if requested() {
+                eprintln!("Exiting without waiting");
+                std::process::exit(130);
+            }
+            println!("Shutting down after the current message; signal again to exit now");
+            request();
+        }
+    });
+    Ok(())
+}
+
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+    NOTIFY.notify_waiters();
+}
+
+/// Whether a shutdown has been asked for. Loops over queued messages check this between
+/// messages; whatever is left stays queued for the next run.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Sleeps for `duration`, waking early on shutdown. Returns whether a shutdown was requested.
+pub async fn sleep_unless_requested(duration: Duration) -> bool {
+    let notified = NOTIFY.notified();
+    if requested() {
+        return true;
+    }
+    tokio::select! {
+        _ = sleep(duration) => requested(),
+        _ = notified => true,
+    }
+}
@@ -0,0 +1,274 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::Result;
+use chrono::DateTime;
+use colored::Colorize;
+
+use crate::{
+    db::{Database, FeedEntry, FeedEntryKind},
+    images::usable_image_url,
+    models::Project,
+    summarizer::html_to_markdown,
+    text::escape_html,
+};
+
+const STYLE: &str = "body { font-family: sans-serif; max-width: 900px; margin: 2em auto; padding: 0 1em; line-height: 1.5; }
+table { border-collapse: collapse; width: 100%; }
+th, td { text-align: left; padding: 0.3em 0.5em; border-bottom: 1px solid #ddd; vertical-align: top; }
+th { cursor: pointer; user-select: none; }
+img { max-width: 100%; height: auto; }
+.meta { color: #555; }
+.description { white-space: pre-wrap; }
+.summary { background: #f4f4f4; padding: 0.5em 1em; border-left: 4px solid #3498db; }";
+
+// Sorts the index table when a header is clicked and filters it by tag. The page works without
+// it, just sorted newest first.
+const INDEX_SCRIPT: &str = "const table = document.getElementById('projects');
+const rows = () => Array.from(table.tBodies[0].rows);
+let sorted = { column: 3, ascending: false };
+table.querySelectorAll('th').forEach((th, column) => th.addEventListener('click', () => {
+  const ascending = sorted.column === column ? !sorted.ascending : true;
+  sorted = { column, ascending };
+  const key = row => row.cells[column].dataset.sort ?? row.cells[column].textContent;
+  rows().sort((a, b) => key(a).localeCompare(key(b)) * (ascending ? 1 : -1))
+    .forEach(row => table.tBodies[0].appendChild(row));
+}));
+document.getElementById('tag').addEventListener('change', e => {
+  rows().forEach(row => {
+    const tags = row.dataset.tags.split('|');
+    row.hidden = e.target.value !== '' && !tags.includes(e.target.value);
+  });
+});";
+
+/// Renders every project in the database into `out_dir`: an `index.html` plus one page per
+/// project under `projects/`. The directory is self-contained and can be copied anywhere.
+pub fn generate_site(db: &Database, out_dir: &Path) -> Result<()> {
+    println!("{}", "Generating site...".bold().cyan());
+
+    let mut projects = db.get_projects()?;
+    projects.sort_by(|a, b| sort_date(b).cmp(sort_date(a)));
+
+    let mut history: HashMap<String, Vec<FeedEntry>> = HashMap::new();
+    // get_feed_entries is newest first; project pages list changes oldest first
+    for entry in db.get_feed_entries(None, None)?.into_iter().rev() {
+        history
+            .entry(entry.project.id.clone())
+            .or_default()
+            .push(entry);
+    }
+
+    fs::create_dir_all(out_dir.join("projects"))?;
+    fs::write(out_dir.join("index.html"), index_page(&projects))?;
+    for project in &projects {
+        let entries = history.get(&project.id).map(Vec::as_slice).unwrap_or(&[]);
+        fs::write(
+            out_dir.join("projects").join(project_file(project)),
+            project_page(project, entries),
+        )?;
+    }
+
+    println!(
+        "Wrote {} project pages to {}",
+        projects.len().to_string().green(),
+        out_dir.display().to_string().green()
+    );
+    Ok(())
+}
+
+/// Published date if the project has one, otherwise when it was created; both are RFC 3339
+fn sort_date(project: &Project) -> &str {
+    project
+        .attributes
+        .published_at
+        .as_deref()
+        .unwrap_or(&project.attributes.created_at)
+}
+
+fn display_date(date: &str) -> String {
+    DateTime::parse_from_rfc3339(date)
+        .map(|d| d.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
+
+/// Project IDs come from the API; don't trust them as file names
+fn project_file(project: &Project) -> String {
+    let id: String = project
+        .id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .collect();
+    format!("{}.html", id)
+}
+
+fn project_name(project: &Project) -> String {
+    project.attributes.name.replace('\n', "").trim().to_string()
+}
+
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape_html(title),
+        STYLE,
+        body
+    )
+}
+
+fn index_page(projects: &[Project]) -> String {
+    let mut tags: Vec<&str> = projects
+        .iter()
+        .flat_map(|p| p.attributes.project_tag_list.iter().map(String::as_str))
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+
+    let mut body = String::from("<h1>ShapeYourCity projects</h1>\n");
+    body.push_str(&format!(
+        "<p class=\"meta\">{} projects. Click a column header to sort.</p>\n",
+        projects.len()
+    ));
+
+    body.push_str("<label>Tag: <select id=\"tag\">\n<option value=\"\">All</option>\n");
+    for tag in tags {
+        body.push_str(&format!("<option>{}</option>\n", escape_html(tag)));
+    }
+    body.push_str("</select></label>\n");
+
+    body.push_str(
+        "<table id=\"projects\">\n<thead><tr><th>Name</th><th>State</th><th>Tags</th><th>Published</th></tr></thead>\n<tbody>\n",
+    );
+    for project in projects {
+        let tags = &project.attributes.project_tag_list;
+        body.push_str(&format!(
+            "<tr data-tags=\"{}\"><td><a href=\"projects/{}\">{}</a></td><td>{}</td><td>{}</td><td data-sort=\"{}\">{}</td></tr>\n",
+            escape_html(&tags.join("|")),
+            project_file(project),
+            escape_html(&project_name(project)),
+            escape_html(&project.attributes.state),
+            escape_html(&tags.join(", ")),
+            escape_html(sort_date(project)),
+            display_date(sort_date(project))
+        ));
+    }
+    body.push_str("</tbody>\n</table>\n");
+    body.push_str(&format!("<script>\n{}\n</script>\n", INDEX_SCRIPT));
+
+    page("ShapeYourCity projects", &body)
+}
+
+fn project_page(project: &Project, history: &[FeedEntry]) -> String {
+    let attributes = &project.attributes;
+    let name = project_name(project);
+
+    let mut body = String::from("<p><a href=\"../index.html\">All projects</a></p>\n");
+    body.push_str(&format!("<h1>{}</h1>\n", escape_html(&name)));
+
+    let mut meta = vec![format!("State: {}", escape_html(&attributes.state))];
+    if !attributes.project_tag_list.is_empty() {
+        meta.push(format!(
+            "Tags: {}",
+            escape_html(&attributes.project_tag_list.join(", "))
+        ));
+    }
+    if let Some(published) = &attributes.published_at {
+        meta.push(format!("Published {}", display_date(published)));
+    }
+    meta.push(format!(
+        "<a href=\"{}\">View on ShapeYourCity</a>",
+        escape_html(&project.links.self_link)
+    ));
+    body.push_str(&format!("<p class=\"meta\">{}</p>\n", meta.join(" · ")));
+
+    if let Some(image_url) = usable_image_url(&attributes.image_url) {
+        body.push_str(&format!(
+            "<p><img src=\"{}\" alt=\"{}\"></p>\n",
+            escape_html(image_url),
+            escape_html(project.image_alt_text().unwrap_or(&name))
+        ));
+    }
+
+    if let Some(summary) = history.iter().find(|e| e.kind == FeedEntryKind::New) {
+        body.push_str(&format!(
+            "<p class=\"summary\">{}</p>\n",
+            escape_html(&summary.summary)
+        ));
+    }
+
+    if let Some(description) = &attributes.description {
+        // The city's HTML is untrusted, so show it as escaped Markdown rather than markup
+        body.push_str(&format!(
+            "<h2>Description</h2>\n<div class=\"description\">{}</div>\n",
+            escape_html(html_to_markdown(description).trim())
+        ));
+    }
+
+    let changes: Vec<&FeedEntry> = history
+        .iter()
+        .filter(|e| e.kind == FeedEntryKind::Changed)
+        .collect();
+    if !changes.is_empty() {
+        body.push_str("<h2>Changes</h2>\n<ul>\n");
+        for change in changes {
+            body.push_str(&format!(
+                "<li>{}: {}</li>\n",
+                change.created_at.format("%Y-%m-%d"),
+                escape_html(&change.summary)
+            ));
+        }
+        body.push_str("</ul>\n");
+    }
+
+    page(&name, &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Projects;
+
+    #[test]
+    fn escapes_descriptions() {
+        let mut project = Project::default();
+        project.attributes.description = Some(
+            r#"<p onclick="alert(1)">Hello <a href="javascript:alert(1)">there</a></p><script>alert(1)</script>
+            <p><strong>6 storeys</strong> &amp; 1 &lt;b&gt;</p>"#
+                .to_string(),
+        );
+
+        let page = project_page(&project, &[]);
+        assert!(page.contains("Hello there"));
+        assert!(page.contains("**6 storeys** &amp; 1"));
+        assert!(!page.contains("<b>"));
+        assert!(!page.contains("<script"));
+        assert!(!page.contains("onclick"));
+        assert!(!page.contains("javascript:"));
+    }
+
+    #[test]
+    fn generates_site() -> Result<()> {
+        let json = include_str!("../test_files/ExampleInput.json");
+        let projects = serde_json::from_str::<Projects>(json)?.data;
+
+        let mut db = Database::new_in_memory()?;
+        db.upsert_projects(&projects)?;
+        let project = &projects[0];
+        db.record_feed_entry(FeedEntryKind::New, project, "A new plan for the city")?;
+        db.record_feed_entry(FeedEntryKind::Changed, project, "description was revised")?;
+
+        let dir = std::env::temp_dir().join(format!("rezoning-site-{}", std::process::id()));
+        generate_site(&db, &dir)?;
+
+        let index = fs::read_to_string(dir.join("index.html"))?;
+        assert!(index.contains(&format!("href=\"projects/{}\"", project_file(project))));
+        assert_eq!(fs::read_dir(dir.join("projects"))?.count(), projects.len());
+
+        let page = fs::read_to_string(dir.join("projects").join(project_file(project)))?;
+        assert!(page.contains("<p class=\"summary\">A new plan for the city</p>"));
+        assert!(page.contains(": description was revised</li>"));
+        assert!(!page.contains("<script"));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,560 @@
+use std::{sync::LazyLock, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::DateTime;
+use colored::Colorize;
+use regex::Regex;
+use sentry::integrations::anyhow::capture_anyhow;
+use serde_json::{json, Value};
+
+use crate::{
+    db::{Database, SlackMessageRef},
+    images::usable_image_url,
+    models::{Project, ProjectUpdate, SummarizedProject},
+    notifier::Notifier,
+    text::truncate,
+};
+
+// Slack rejects header blocks with more than 150 characters of text
+const MAX_HEADER_LENGTH: usize = 150;
+
+const DEFAULT_API_URL: &str = "https://slack.com/api";
+
+/// Where Slack messages go: an incoming webhook, or a bot that can thread replies and edit
+/// its own messages
+pub enum SlackBackend {
+    Webhook {
+        url: String,
+        client: reqwest::Client,
+    },
+    Bot(SlackBot),
+}
+
+impl SlackBackend {
+    pub fn webhook(url: String) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(20))
+            .build()?;
+
+        Ok(SlackBackend::Webhook { url, client })
+    }
+}
+
+/// Posts through the Slack Web API with a bot token (`xoxb-...`)
+pub struct SlackBot {
+    token: String,
+    default_channel: String,
+    /// (tag, channel) pairs; the first tag the project has decides the channel
+    tag_channels: Vec<(String, String)>,
+    api_url: String,
+    client: reqwest::Client,
+}
+
+impl SlackBot {
+    pub fn new(
+        token: String,
+        default_channel: String,
+        tag_channels: Vec<(String, String)>,
+        api_url: Option<&str>,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(20))
+            .build()?;
+
+        Ok(SlackBot {
+            token,
+            default_channel,
+            tag_channels,
+            api_url: api_url
+                .unwrap_or(DEFAULT_API_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            client,
+        })
+    }
+
+    fn channel_for(&self, project: &Project) -> &str {
+        let tags = &project.attributes.project_tag_list;
+
+        self.tag_channels
+            .iter()
+            .find(|(tag, _)| tags.iter().any(|t| t.eq_ignore_ascii_case(tag)))
+            .map(|(_, channel)| channel.as_str())
+            .unwrap_or(&self.default_channel)
+    }
+
+    pub async fn post_project(&self, project: &SummarizedProject) -> Result<SlackMessageRef> {
+        println!("{}", "Posting to Slack...".bold().cyan());
+
+        let mut message = slack_message(project);
+        message["channel"] = json!(self.channel_for(&project.project));
+
+        let response = self.call("chat.postMessage", &message).await?;
+
+        println!("{}", "Posted message to Slack".green());
+
+        Ok(SlackMessageRef {
+            channel: string_field(&response, "channel")?,
+            ts: string_field(&response, "ts")?,
+            tweet: project.tweet.clone(),
+        })
+    }
+
+    /// Replies in the original message's thread
+    pub async fn post_update(
+        &self,
+        update: &ProjectUpdate,
+        original: &SlackMessageRef,
+    ) -> Result<()> {
+        println!("{}", "Posting update to Slack...".bold().cyan());
+
+        let reply = format!("Update: {}", escape_mrkdwn(&update.describe_changes()));
+        self.call(
+            "chat.postMessage",
+            &json!({
+                "channel": original.channel,
+                "thread_ts": original.ts,
+                "text": reply
+            }),
+        )
+        .await?;
+
+        println!("{}", "Posted update to Slack".green());
+        Ok(())
+    }
+
+    /// Rewrites the original message with the project's current details, so it doesn't show a
+    /// stale state
+    pub async fn refresh_original(
+        &self,
+        update: &ProjectUpdate,
+        original: &SlackMessageRef,
+    ) -> Result<()> {
+        let mut message = slack_message(&SummarizedProject {
+            project: update.project.clone(),
+            tweet: original.tweet.clone(),
+        });
+        message["channel"] = json!(original.channel);
+        message["ts"] = json!(original.ts);
+
+        self.call("chat.update", &message).await?;
+
+        println!("{}", "Updated original Slack message".green());
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, body: &Value) -> Result<Value> {
+        let response: Value = self
+            .client
+            .post(format!("{}/{}", self.api_url, method))
+            .bearer_auth(&self.token)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        // The Web API returns 200 for most errors and reports them in the body
+        if response["ok"].as_bool() != Some(true) {
+            bail!(
+                "Slack {} failed: {}",
+                method,
+                response["error"].as_str().unwrap_or("unknown error")
+            );
+        }
+
+        Ok(response)
+    }
+}
+
+impl Notifier for SlackBackend {
+    type Message = SummarizedProject;
+
+    const NAME: &'static str = "Slack";
+    const QUEUE_NAME: &'static str = "slack_post_queue";
+
+    async fn send(&self, db: &Database, project: &SummarizedProject) -> Result<()> {
+        match self {
+            SlackBackend::Webhook { url, client } => {
+                post_to_slack(client, url, create_slack_message(project)).await
+            }
+            SlackBackend::Bot(bot) => {
+                let project_id = &project.project.id;
+                if db.get_slack_message(project_id)?.is_some() {
+                    eprintln!("Project {} is already on Slack; skipping", project_id);
+                    return Ok(());
+                }
+
+                let posted = bot.post_project(project).await?;
+                // The message is out, so failing here would post it again on the retry. Its
+                // updates just won't be threaded.
+                if let Err(e) = db.record_slack_message(project_id, &posted) {
+                    eprintln!("Couldn't record Slack message: {}", e);
+                    capture_anyhow(&e.context("Posted to Slack but couldn't record the message"));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Threads change notifications under the bot's original messages
+pub struct SlackUpdateNotifier<'a> {
+    pub bot: &'a SlackBot,
+}
+
+impl Notifier for SlackUpdateNotifier<'_> {
+    type Message = ProjectUpdate;
+
+    const NAME: &'static str = "Slack updates";
+    const QUEUE_NAME: &'static str = "slack_update_queue";
+
+    async fn send(&self, db: &Database, update: &ProjectUpdate) -> Result<()> {
+        let project_id = &update.project.id;
+        let Some(original) = db.get_slack_message(project_id)? else {
+            eprintln!(
+                "No Slack message for project {}; skipping update",
+                project_id
+            );
+            return Ok(());
+        };
+
+        self.bot.post_update(update, &original).await
+    }
+}
+
+/// Edits the bot's original messages after a state change. Queued separately from the thread
+/// reply, so retrying one doesn't repeat the other.
+pub struct SlackRefreshNotifier<'a> {
+    pub bot: &'a SlackBot,
+}
+
+impl Notifier for SlackRefreshNotifier<'_> {
+    type Message = ProjectUpdate;
+
+    const NAME: &'static str = "Slack refreshes";
+    const QUEUE_NAME: &'static str = "slack_refresh_queue";
+
+    async fn send(&self, db: &Database, update: &ProjectUpdate) -> Result<()> {
+        let project_id = &update.project.id;
+        let Some(original) = db.get_slack_message(project_id)? else {
+            eprintln!(
+                "No Slack message for project {}; skipping refresh",
+                project_id
+            );
+            return Ok(());
+        };
+
+        self.bot.refresh_original(update, &original).await
+    }
+}
+
+fn string_field(response: &Value, field: &str) -> Result<String> {
+    response[field]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("Slack response is missing {}", field))
+}
+
+/// Parses a `--slack-tag-channel` value like `Rezoning=#rezonings`
+pub fn parse_tag_channel(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((tag, channel)) if !tag.trim().is_empty() && !channel.trim().is_empty() => {
+            Ok((tag.trim().to_string(), channel.trim().to_string()))
+        }
+        _ => Err(format!("expected TAG=CHANNEL, got {:?}", value)),
+    }
+}
+
+pub async fn post_to_slack(
+    client: &reqwest::Client,
+    webhook_url: &str,
+    message: String,
+) -> Result<()> {
+    println!("{}", "Posting to Slack...".bold().cyan());
+
+    client
+        .post(webhook_url)
+        .header("Content-Type", "application/json")
+        .body(message)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    println!("{}", "Posted message to Slack".green());
+    Ok(())
+}
+
+/// Builds a Block Kit message. `text` is the plain fallback used in notifications and by
+/// clients that can't render blocks.
+pub fn create_slack_message(project: &SummarizedProject) -> String {
+    slack_message(project).to_string()
+}
+
+fn slack_message(project: &SummarizedProject) -> Value {
+    let SummarizedProject { project, tweet } = project;
+    let attributes = &project.attributes;
+
+    let name = attributes.name.replace('\n', "").trim().to_string();
+    let link = &project.links.self_link;
+
+    let fallback = format!(
+        "*<{}|{}>*\n{}",
+        link,
+        escape_mrkdwn(&name),
+        escape_mrkdwn(tweet)
+    );
+
+    let mut blocks = vec![json!({
+        "type": "header",
+        "text": {
+            "type": "plain_text",
+            "text": truncate(&name, MAX_HEADER_LENGTH),
+            "emoji": true
+        }
+    })];
+
+    let mut summary = json!({
+        "type": "section",
+        "text": {
+            "type": "mrkdwn",
+            "text": format!("{}\n<{}|View on ShapeYourCity>", escape_mrkdwn(tweet), link)
+        }
+    });
+    if let Some(image_url) = usable_image_url(&attributes.image_url) {
+        let alt_text = project.image_alt_text().unwrap_or(&name);
+        summary["accessory"] = json!({
+            "type": "image",
+            "image_url": image_url,
+            "alt_text": alt_text
+        });
+    }
+    blocks.push(summary);
+
+    let mut fields = vec![field("State", &attributes.state)];
+    if !attributes.project_tag_list.is_empty() {
+        fields.push(field("Tags", &attributes.project_tag_list.join(", ")));
+    }
+    if let Some(application_number) = application_number(&name) {
+        fields.push(field("Application", application_number));
+    }
+    blocks.push(json!({
+        "type": "section",
+        "fields": fields
+    }));
+
+    if let Some(published) = attributes
+        .published_at
+        .as_deref()
+        .and_then(|p| DateTime::parse_from_rfc3339(p).ok())
+    {
+        // Slack renders this in each reader's own time zone
+        blocks.push(json!({
+            "type": "context",
+            "elements": [{
+                "type": "mrkdwn",
+                "text": format!(
+                    "<!date^{}^Published {{date_long}}|Published {}>",
+                    published.timestamp(),
+                    published.format("%Y-%m-%d")
+                )
+            }]
+        }));
+    }
+
+    json!({
+        "text": fallback,
+        "blocks": blocks
+    })
+}
+
+fn field(label: &str, value: &str) -> Value {
+    json!({
+        "type": "mrkdwn",
+        "text": format!("*{}*\n{}", label, escape_mrkdwn(value))
+    })
+}
+
+/// Development permit numbers appear in project names, e.g. "524-528 Powell St (DP-2020-00287)"
+static APPLICATION_NUMBER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\b[A-Z]{2,3}-\d{4}-\d{5}\b").unwrap());
+
+fn application_number(name: &str) -> Option<&str> {
+    APPLICATION_NUMBER.find(name).map(|m| m.as_str())
+}
+
+/// Slack treats these three characters as control characters in mrkdwn
+fn escape_mrkdwn(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{ProjectChange, Projects},
+        test_server::{self, Reply},
+    };
+
+    fn example_project(name_prefix: &str) -> SummarizedProject {
+        let json = include_str!("../test_files/ExampleInput.json");
+        let projects = serde_json::from_str::<Projects>(json).unwrap();
+        let project = projects
+            .data
+            .into_iter()
+            .find(|p| p.attributes.name.starts_with(name_prefix))
+            .unwrap();
+
+        SummarizedProject {
+            project,
+            tweet: "524-528 Powell St. 6 storeys, 3.5 FSR, 69 rental units & retail".to_string(),
+        }
+    }
+
+    #[test]
+    fn slack_message_snapshot() {
+        let message = create_slack_message(&example_project("524-528 Powell St"));
+
+        let actual: Value = serde_json::from_str(&message).unwrap();
+        let expected: Value =
+            serde_json::from_str(include_str!("../test_files/slack_message.json")).unwrap();
+
+        assert_eq!(
+            actual,
+            expected,
+            "Slack message changed, actual:\n{}",
+            serde_json::to_string_pretty(&actual).unwrap()
+        );
+    }
+
+    #[test]
+    fn routes_channels_by_tag() -> Result<()> {
+        let bot = SlackBot::new(
+            "xoxb-test".to_string(),
+            "#general".to_string(),
+            vec![
+                ("rezoning".to_string(), "#rezonings".to_string()),
+                ("Development".to_string(), "#dps".to_string()),
+            ],
+            None,
+        )?;
+
+        let project = example_project("524-528 Powell St").project;
+        assert_eq!(bot.channel_for(&project), "#dps");
+
+        let mut project = project;
+        project.attributes.project_tag_list = vec!["Rezoning".to_string()];
+        assert_eq!(bot.channel_for(&project), "#rezonings");
+
+        project.attributes.project_tag_list.clear();
+        assert_eq!(bot.channel_for(&project), "#general");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn does_not_repost_recorded_projects() -> Result<()> {
+        let db = Database::new_in_memory()?;
+        let project = example_project("524-528 Powell St");
+        let posted = SlackMessageRef {
+            channel: "C123".to_string(),
+            ts: "1700000000.000100".to_string(),
+            tweet: project.tweet.clone(),
+        };
+        db.record_slack_message(&project.project.id, &posted)?;
+
+        // a retry after the post was recorded must not reach the API
+        let bot = SlackBot::new(
+            "xoxb-test".to_string(),
+            "#general".to_string(),
+            vec![],
+            None,
+        )?;
+        SlackBackend::Bot(bot).send(&db, &project).await?;
+
+        assert_eq!(db.get_slack_message(&project.project.id)?, Some(posted));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn threads_updates_and_edits_originals() -> Result<()> {
+        let (api_url, server) = test_server::start(vec![
+            Reply::json(
+                200,
+                r#"{"ok":true,"channel":"C123","ts":"1700000000.000200"}"#,
+            ),
+            Reply::json(
+                200,
+                r#"{"ok":true,"channel":"C123","ts":"1700000000.000100"}"#,
+            ),
+            Reply::json(200, r#"{"ok":false,"error":"message_not_found"}"#),
+        ])
+        .await;
+
+        let project = example_project("524-528 Powell St");
+        let original = SlackMessageRef {
+            channel: "C123".to_string(),
+            ts: "1700000000.000100".to_string(),
+            tweet: project.tweet.clone(),
+        };
+        let update = ProjectUpdate {
+            project: project.project,
+            changes: vec![ProjectChange {
+                field: "state".to_string(),
+                old_value: "published".to_string(),
+                new_value: "archived".to_string(),
+            }],
+        };
+
+        let bot = SlackBot::new(
+            "xoxb-test".to_string(),
+            "#general".to_string(),
+            vec![],
+            Some(&api_url),
+        )?;
+        bot.post_update(&update, &original).await?;
+        bot.refresh_original(&update, &original).await?;
+        let error = bot
+            .refresh_original(&update, &original)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, "Slack chat.update failed: message_not_found");
+
+        let requests = server.await?;
+        assert!(requests[0].starts_with("POST /chat.postMessage "));
+        assert!(requests[0].contains("Bearer xoxb-test"));
+        assert!(requests[0].contains(r#""thread_ts":"1700000000.000100""#));
+        assert!(requests[0].contains("status changed from published to archived"));
+        assert!(requests[1].starts_with("POST /chat.update "));
+        assert!(requests[1].contains(r#""ts":"1700000000.000100""#));
+        assert!(requests[1].contains(r#""channel":"C123""#));
+        assert!(!requests[1].contains("thread_ts"));
+        Ok(())
+    }
+
+    #[test]
+    fn parses_tag_channels() {
+        assert_eq!(
+            parse_tag_channel("Rezoning = #rezonings"),
+            Ok(("Rezoning".to_string(), "#rezonings".to_string()))
+        );
+        assert!(parse_tag_channel("Rezoning").is_err());
+        assert!(parse_tag_channel("=C123").is_err());
+    }
+
+    #[test]
+    fn extracts_application_numbers() {
+        assert_eq!(
+            application_number("524-528 Powell St (DP-2020-00287) development application"),
+            Some("DP-2020-00287")
+        );
+        assert_eq!(
+            application_number("2924 Venables St rezoning application"),
+            None
+        );
+    }
+}
@@ -5,7 +5,7 @@ use std::collections::HashMap;
 
 use crate::models::Project;
 
-const MODEL_ANTHROPIC: &str = "claude-3-5-haiku-20241022";
+pub const DEFAULT_MODEL: &str = "claude-3-5-haiku-20241022";
 
 /// Convert HTML to Markdown, ignoring images and not including URLs
 pub fn html_to_markdown(html: &str) -> String {
@@ -16,9 +16,9 @@ pub fn html_to_markdown(html: &str) -> String {
     html2md::parse_html_custom(html, &handlers)
 }
 
-// This currently expects an Anthropic API key to be set in the environment
-// TODO: make auth more flexible
-pub async fn project_to_tweet(proj: &Project) -> Result<String> {
+// genai picks the provider from the model name and reads its API key (e.g. ANTHROPIC_API_KEY)
+// from the environment
+pub async fn project_to_tweet(proj: &Project, model: &str) -> Result<String> {
     let mut user_message = "Summarize this:\n".to_string();
     user_message += &format!("# {}\n", proj.attributes.name.replace('\n', ""));
     let description_html = &proj.attributes.description.clone().unwrap_or_default();
@@ -32,9 +32,7 @@ pub async fn project_to_tweet(proj: &Project) -> Result<String> {
         ChatMessage::user(user_message),
     ]);
 
-    let chat_res = client
-        .exec_chat(MODEL_ANTHROPIC, chat_req.clone(), None)
-        .await?;
+    let chat_res = client.exec_chat(model, chat_req.clone(), None).await?;
 
     let response = chat_res
         .content_text_as_str()
@@ -89,7 +87,7 @@ mod tests {
         handlers.insert("img".to_string(), Box::new(IgnoreHandlerFactory));
         handlers.insert("a".to_string(), Box::new(TextOnlyHandlerFactory));
 
-        let md = html2md::parse_html_custom(&description, &handlers);
+        let md = html2md::parse_html_custom(description, &handlers);
 
         let expected = "Matthew Cheng Architect Inc. has applied to the City of Vancouver for permission to develop the following on this site:
 
@@ -0,0 +1,295 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    db::Database, images::usable_image_url, models::SummarizedProject, notifier::Notifier,
+    shutdown, text::truncate,
+};
+
+pub const DEFAULT_API_URL: &str = "https://api.telegram.org";
+
+// Telegram's limits, counted after entity parsing; leave room for the escaping and formatting
+const MAX_CAPTION_CHARS: usize = 900;
+const MAX_MESSAGE_CHARS: usize = 3500;
+
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+// Flood control can ask for hours; fail the message and let the queue retry it on a later run
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+/// One project to send to one chat, so a failure in one chat doesn't resend to the others
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelegramMessage {
+    pub chat_id: String,
+    pub project: SummarizedProject,
+}
+
+pub struct TelegramBot {
+    api_url: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl TelegramBot {
+    pub fn new(token: &str, api_url: Option<&str>) -> Result<Self> {
+        let api_url = api_url
+            .unwrap_or(DEFAULT_API_URL)
+            .trim()
+            .trim_end_matches('/');
+        let parsed = reqwest::Url::parse(api_url)
+            .map_err(|e| anyhow!("Invalid Telegram API URL {}: {}", api_url, e))?;
+        if parsed.scheme() != "https" && parsed.scheme() != "http" {
+            bail!("Telegram API URL must be http(s): {}", api_url);
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(20))
+            .build()?;
+
+        Ok(TelegramBot {
+            api_url: api_url.to_string(),
+            token: token.to_string(),
+            client,
+        })
+    }
+
+    /// Sends the project's image with the summary as its caption, or just the summary if there's
+    /// no usable image or Telegram can't fetch it
+    pub async fn send_project(&self, message: &TelegramMessage) -> Result<()> {
+        println!("{}", "Posting to Telegram...".bold().cyan());
+        let project = &message.project;
+        let button = json!({
+            "inline_keyboard": [[{
+                "text": "Open on ShapeYourCity",
+                "url": project.project.links.self_link
+            }]]
+        });
+
+        if let Some(image_url) = usable_image_url(&project.project.attributes.image_url) {
+            let result = self
+                .call(
+                    "sendPhoto",
+                    &json!({
+                        "chat_id": message.chat_id,
+                        "photo": image_url,
+                        "caption": format_text(project, MAX_CAPTION_CHARS),
+                        "parse_mode": "MarkdownV2",
+                        "reply_markup": button
+                    }),
+                )
+                .await;
+
+            match result {
+                Ok(_) => {
+                    println!("{}", "Posted photo to Telegram".green());
+                    return Ok(());
+                }
+                Err(e) => eprintln!("Telegram couldn't send the photo, sending text: {}", e),
+            }
+        }
+
+        self.call(
+            "sendMessage",
+            &json!({
+                "chat_id": message.chat_id,
+                "text": format_text(project, MAX_MESSAGE_CHARS),
+                "parse_mode": "MarkdownV2",
+                "link_preview_options": { "is_disabled": true },
+                "reply_markup": button
+            }),
+        )
+        .await?;
+
+        println!("{}", "Posted message to Telegram".green());
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, body: &Value) -> Result<Value> {
+        let url = format!("{}/bot{}/{}", self.api_url, self.token, method);
+        let mut retries = 0;
+
+        loop {
+            // reqwest errors include the URL, which contains the bot token
+            let response = self
+                .client
+                .post(&url)
+                .json(body)
+                .send()
+                .await
+                .map_err(|e| e.without_url())?;
+            let status = response.status();
+            let response: Value = response.json().await.map_err(|e| e.without_url())?;
+
+            if status == StatusCode::TOO_MANY_REQUESTS && retries < MAX_RATE_LIMIT_RETRIES {
+                retries += 1;
+                let wait = Duration::from_secs(
+                    response["parameters"]["retry_after"].as_u64().unwrap_or(1),
+                );
+                if wait > MAX_RATE_LIMIT_WAIT {
+                    bail!(
+                        "Telegram rate limited us for {}s; leaving the message for the next run",
+                        wait.as_secs()
+                    );
+                }
+                eprintln!("Rate limited by Telegram; waiting {}s", wait.as_secs());
+                if shutdown::sleep_unless_requested(wait).await {
+                    bail!("Shutting down; leaving the message for the next run");
+                }
+                continue;
+            }
+
+            if response["ok"].as_bool() != Some(true) {
+                bail!(
+                    "Telegram {} failed ({}): {}",
+                    method,
+                    status,
+                    response["description"].as_str().unwrap_or("unknown error")
+                );
+            }
+
+            return Ok(response["result"].clone());
+        }
+    }
+}
+
+impl Notifier for TelegramBot {
+    type Message = TelegramMessage;
+
+    const NAME: &'static str = "Telegram";
+    const QUEUE_NAME: &'static str = "telegram_post_queue";
+
+    async fn send(&self, _db: &Database, message: &TelegramMessage) -> Result<()> {
+        self.send_project(message).await
+    }
+}
+
+/// Bold title, then the summary and tags, escaped for MarkdownV2
+fn format_text(project: &SummarizedProject, max_chars: usize) -> String {
+    let SummarizedProject { project, tweet } = project;
+    let title = project.display_title();
+
+    let mut text = format!(
+        "*{}*\n\n{}",
+        escape_markdown_v2(&title),
+        escape_markdown_v2(&truncate(tweet, max_chars))
+    );
+    if !project.attributes.project_tag_list.is_empty() {
+        text.push_str(&format!(
+            "\n\n_{}_",
+            escape_markdown_v2(&project.attributes.project_tag_list.join(", "))
+        ));
+    }
+    text
+}
+
+/// Every one of these characters has to be escaped anywhere in MarkdownV2 text
+fn escape_markdown_v2(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "_*[]()~`>#+-=|{}.!\\".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::Project,
+        test_server::{self, Reply},
+    };
+
+    fn message(image_url: Option<&str>) -> TelegramMessage {
+        let mut project = Project {
+            id: "foo".to_string(),
+            ..Default::default()
+        };
+        project.attributes.name = "1234-1250 W. 10th Ave (DP-2024-00123)".to_string();
+        project.attributes.project_tag_list = vec!["Development".to_string()];
+        project.attributes.image_url = image_url.map(String::from);
+        project.links.self_link = "https://shapeyourcity.ca/1234-w-10th".to_string();
+
+        TelegramMessage {
+            chat_id: "-100123".to_string(),
+            project: SummarizedProject {
+                project,
+                tweet: "6 storeys, 3.5 FSR!".to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn escapes_markdown_v2() {
+        assert_eq!(
+            format_text(&message(None).project, MAX_MESSAGE_CHARS),
+            "*DP: 1234\\-1250 W\\. 10th Ave \\(DP\\-2024\\-00123\\)*\n\n6 storeys, 3\\.5 FSR\\!\n\n_Development_"
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_text_when_photo_fails() -> Result<()> {
+        let (api_url, server) = test_server::start(vec![
+            Reply::json(
+                400,
+                r#"{"ok":false,"description":"Bad Request: wrong file identifier"}"#,
+            ),
+            Reply::json(200, r#"{"ok":true,"result":{"message_id":1}}"#),
+        ])
+        .await;
+
+        let bot = TelegramBot::new("123:abc", Some(&api_url))?;
+        bot.send_project(&message(Some("https://example.com/a.jpg")))
+            .await?;
+
+        let requests = server.await?;
+        assert!(requests[0].starts_with("POST /bot123:abc/sendPhoto "));
+        assert!(requests[0].contains(r#""photo":"https://example.com/a.jpg""#));
+        assert!(requests[1].starts_with("POST /bot123:abc/sendMessage "));
+        assert!(requests[1].contains(r#""url":"https://shapeyourcity.ca/1234-w-10th""#));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_api_errors_without_the_token() -> Result<()> {
+        let (api_url, _server) = test_server::start(vec![Reply::json(
+            403,
+            r#"{"ok":false,"description":"Forbidden: bot was kicked"}"#,
+        )])
+        .await;
+
+        let bot = TelegramBot::new("123:secret", Some(&api_url))?;
+        let error = bot
+            .send_project(&message(None))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("bot was kicked"));
+        assert!(!error.contains("secret"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_long_flood_waits() -> Result<()> {
+        let (api_url, _server) = test_server::start(vec![Reply::json(
+            429,
+            r#"{"ok":false,"description":"Too Many Requests","parameters":{"retry_after":7200}}"#,
+        )])
+        .await;
+
+        let bot = TelegramBot::new("123:abc", Some(&api_url))?;
+        let error = bot
+            .send_project(&message(None))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("7200s"));
+        Ok(())
+    }
+}
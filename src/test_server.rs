@@ -0,0 +1,90 @@
+//! A stand-in HTTP server for testing API clients without the network
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// A canned response
+pub struct Reply {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn new(status: u16) -> Self {
+        Reply {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn json(status: u16, body: &str) -> Self {
+        Reply::new(status)
+            .header("Content-Type", "application/json")
+            .body(body.as_bytes())
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: &[u8]) -> Self {
+        self.body = body.to_vec();
+        self
+    }
+}
+
+/// Answers each request with the next reply and returns the requests (head and body)
+pub async fn serve(listener: TcpListener, replies: Vec<Reply>) -> Vec<String> {
+    let mut requests = Vec::new();
+    for reply in replies {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        // read until the whole body has arrived
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            if n == 0 {
+                requests.push(String::from_utf8_lossy(&request).to_string());
+                break;
+            }
+            let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") else {
+                continue;
+            };
+            let headers = String::from_utf8_lossy(&request[..end]).to_lowercase();
+            let length = headers
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length: "))
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if request.len() - end - 4 >= length {
+                requests.push(String::from_utf8_lossy(&request).to_string());
+                break;
+            }
+        }
+
+        let mut response = format!("HTTP/1.1 {} X\r\n", reply.status);
+        for (name, value) in &reply.headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            reply.body.len()
+        ));
+        socket.write_all(response.as_bytes()).await.unwrap();
+        socket.write_all(&reply.body).await.unwrap();
+    }
+    requests
+}
+
+/// Binds a port and starts serving `replies`, returning the server's URL and its requests
+pub async fn start(replies: Vec<Reply>) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    (url, tokio::spawn(serve(listener, replies)))
+}
@@ -0,0 +1,40 @@
+/// Shortens text to at most `max_chars` characters, marking the cut with an ellipsis
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else if max_chars == 0 {
+        String::new()
+    } else {
+        let truncated: String = text.chars().take(max_chars - 1).collect();
+        format!("{}…", truncated.trim_end())
+    }
+}
+
+/// Escapes text for use in HTML/XML element content and double-quoted attributes
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("a long sentence", 7), "a long…");
+        assert_eq!(truncate(&"é".repeat(200), 150).chars().count(), 150);
+        assert_eq!(truncate("anything", 0), "");
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">Tom & Jerry</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&lt;/a&gt;"
+        );
+    }
+}
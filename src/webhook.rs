@@ -0,0 +1,238 @@
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use colored::Colorize;
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db::Database,
+    models::{Project, ProjectChange, SummarizedProject},
+    notifier::Notifier,
+};
+
+/// Bumped whenever the event payload changes incompatibly
+pub const EVENT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventType {
+    #[serde(rename = "project.created")]
+    Created,
+    #[serde(rename = "project.changed")]
+    Changed,
+    #[serde(rename = "project.removed")]
+    Removed,
+}
+
+impl EventType {
+    fn as_str(self) -> &'static str {
+        match self {
+            EventType::Created => "project.created",
+            EventType::Changed => "project.changed",
+            EventType::Removed => "project.removed",
+        }
+    }
+}
+
+/// The JSON body POSTed to the webhook
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub version: u32,
+    /// Unique per event, so receivers can ignore redeliveries
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: EventType,
+    /// RFC 3339
+    pub occurred_at: String,
+    pub project: Project,
+    /// The LLM summary; only set for project.created
+    pub summary: Option<String>,
+    /// Only non-empty for project.changed
+    pub changes: Vec<ProjectChange>,
+}
+
+impl WebhookEvent {
+    fn new(
+        event_type: EventType,
+        project: Project,
+        summary: Option<String>,
+        changes: Vec<ProjectChange>,
+    ) -> Self {
+        let now = Utc::now();
+        WebhookEvent {
+            version: EVENT_VERSION,
+            id: format!(
+                "{}-{}-{}",
+                event_type.as_str(),
+                project.id,
+                now.timestamp_micros()
+            ),
+            event_type,
+            occurred_at: now.to_rfc3339(),
+            project,
+            summary,
+            changes,
+        }
+    }
+
+    pub fn created(summarized: &SummarizedProject) -> Self {
+        Self::new(
+            EventType::Created,
+            summarized.project.clone(),
+            Some(summarized.tweet.clone()),
+            vec![],
+        )
+    }
+
+    pub fn changed(project: &Project, changes: &[ProjectChange]) -> Self {
+        Self::new(EventType::Changed, project.clone(), None, changes.to_vec())
+    }
+
+    pub fn removed(project: &Project) -> Self {
+        Self::new(EventType::Removed, project.clone(), None, vec![])
+    }
+}
+
+/// An endpoint that receives every event, signed with a shared secret
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: String,
+    client: reqwest::Client,
+}
+
+impl WebhookConfig {
+    pub fn new(url: &str, secret: &str) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url)?;
+        if parsed.scheme() != "https" && parsed.scheme() != "http" {
+            bail!("Webhook URL must be http(s): {}", url);
+        }
+        if secret.is_empty() {
+            bail!("Webhook secret must not be empty");
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(20))
+            .build()?;
+
+        Ok(WebhookConfig {
+            url: url.to_string(),
+            secret: secret.to_string(),
+            client,
+        })
+    }
+}
+
+/// Posts an event. Receivers verify it by computing the HMAC-SHA256 of
+/// `{X-Rezoning-Timestamp}.{body}` with the shared secret and comparing it to
+/// `X-Rezoning-Signature`; the timestamp lets them reject old replays.
+pub async fn deliver(config: &WebhookConfig, event: &WebhookEvent) -> Result<()> {
+    println!(
+        "{}",
+        format!("Sending {} webhook...", event.event_type.as_str())
+            .bold()
+            .cyan()
+    );
+    let body = serde_json::to_string(event)?;
+    let timestamp = Utc::now().timestamp();
+
+    config
+        .client
+        .post(&config.url)
+        .header("Content-Type", "application/json")
+        .header("X-Rezoning-Event", event.event_type.as_str())
+        .header("X-Rezoning-Delivery", &event.id)
+        .header("X-Rezoning-Timestamp", timestamp.to_string())
+        .header(
+            "X-Rezoning-Signature",
+            sign(&config.secret, timestamp, &body),
+        )
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    println!("{}", "Sent webhook".green());
+    Ok(())
+}
+
+impl Notifier for WebhookConfig {
+    type Message = WebhookEvent;
+
+    const NAME: &'static str = "webhook";
+    const QUEUE_NAME: &'static str = "webhook_queue";
+
+    async fn send(&self, _db: &Database, event: &WebhookEvent) -> Result<()> {
+        deliver(self, event).await
+    }
+}
+
+/// `sha256=<hex HMAC-SHA256 of "{timestamp}.{body}">`
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={}", hex::encode(tag.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> Project {
+        Project {
+            id: "foo".to_string(),
+            project_type: "projects".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn serializes_versioned_events() {
+        let event = WebhookEvent::changed(
+            &project(),
+            &[ProjectChange {
+                field: "state".to_string(),
+                old_value: "published".to_string(),
+                new_value: "archived".to_string(),
+            }],
+        );
+
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["type"], "project.changed");
+        assert_eq!(json["project"]["id"], "foo");
+        assert_eq!(json["summary"], serde_json::Value::Null);
+        assert_eq!(json["changes"][0]["new_value"], "archived");
+        assert!(event.id.starts_with("project.changed-foo-"));
+
+        let created = WebhookEvent::created(&SummarizedProject {
+            project: project(),
+            tweet: "6 storeys".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_value(&created).unwrap()["summary"],
+            "6 storeys"
+        );
+    }
+
+    #[test]
+    fn signs_payloads() {
+        // Python: hmac.new(b"secret", b"1700000000.{}", hashlib.sha256).hexdigest()
+        assert_eq!(
+            sign("secret", 1_700_000_000, "{}"),
+            "sha256=b8569b78799ff9e3cbff0fc2d63a33a2b57f3282abd07c37ae5e8e7d79a5f163"
+        );
+        assert_ne!(
+            sign("secret", 1_700_000_000, "{}"),
+            sign("other", 1_700_000_000, "{}")
+        );
+    }
+
+    #[test]
+    fn validates_config() {
+        assert!(WebhookConfig::new("https://example.com/hook", "s3cret").is_ok());
+        assert!(WebhookConfig::new("ftp://example.com/hook", "s3cret").is_err());
+        assert!(WebhookConfig::new("https://example.com/hook", "").is_err());
+    }
+}